@@ -0,0 +1,221 @@
+use crate::config::{Config, Scope};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::*;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// name of the subcommand which mints jwt tokens
+pub const TOKEN_COMMAND: &str = "token";
+
+/// claims of a jwt api token, signed with HS256.
+///
+/// Lotus api tokens are accepted as well, their `Allow` permissions are mapped to scopes
+/// by `lotus_scopes`. Lotus doesn't set `exp`, so a token without `exp` never expires and
+/// must be revoked by changing `jwt_secret`; tokens minted by this server always have it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    /// expire time in unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    /// who the token is issued to, jobs are owned by it instead of the token itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(alias = "Allow", deserialize_with = "lotus_scopes")]
+    pub scopes: Vec<Scope>,
+}
+
+/// scopes of permission names, which are either scopes of this server or lotus permissions
+/// `read`, `write`, `sign` and `admin`. unknown names grant nothing
+fn lotus_scopes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Scope>, D::Error> {
+    let names = Vec::<String>::deserialize(deserializer)?;
+    let mut scopes = vec![];
+
+    for name in names {
+        match name.as_str() {
+            "read" => scopes.push(Scope::JobsRead),
+            "write" => scopes.extend(&[Scope::SealSubmit, Scope::PostGenerate, Scope::JobsWrite]),
+            // signing with wallet keys has no counterpart here
+            "sign" => {}
+            _ => match serde_json::from_value(json!(name)) {
+                Ok(scope) => scopes.push(scope),
+                Err(_) => debug!("ignore unknown jwt permission {}", name),
+            },
+        }
+    }
+
+    Ok(scopes)
+}
+
+/// prefix of a base64 encoded binary `jwt_secret`, e.g. the key lotus signs its tokens with
+const BASE64_PREFIX: &str = "base64:";
+
+fn encoding_key(secret: &str) -> Result<EncodingKey, String> {
+    match secret.strip_prefix(BASE64_PREFIX) {
+        Some(secret) => EncodingKey::from_base64_secret(secret).map_err(|e| format!("{:?}", e)),
+        None => Ok(EncodingKey::from_secret(secret.as_bytes())),
+    }
+}
+
+fn decoding_key(secret: &str) -> Result<DecodingKey<'static>, String> {
+    match secret.strip_prefix(BASE64_PREFIX) {
+        Some(secret) => DecodingKey::from_base64_secret(secret).map_err(|e| format!("{:?}", e)),
+        None => Ok(DecodingKey::from_secret(secret.as_bytes()).into_static()),
+    }
+}
+
+fn now_secs() -> Result<u64, String> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .map_err(|e| format!("{:?}", e))
+}
+
+/// mint a token for `subject` granting `scopes` which expires after `ttl_secs`
+pub fn mint(secret: &str, subject: Option<&str>, scopes: Vec<Scope>, ttl_secs: u64) -> Result<String, String> {
+    let claims = Claims {
+        exp: Some(now_secs()? + ttl_secs),
+        sub: subject.map(|x| x.to_string()),
+        scopes,
+    };
+
+    encode(&Header::new(Algorithm::HS256), &claims, &encoding_key(secret)?).map_err(|e| format!("{:?}", e))
+}
+
+/// claims of a valid token, `None` if signature is wrong or token expired
+pub fn verify(secret: &str, token: &str) -> Option<Claims> {
+    let key = match decoding_key(secret) {
+        Ok(key) => key,
+        Err(e) => {
+            warn!("invalid jwt_secret: {}", e);
+            return None;
+        }
+    };
+    // `exp` is optional, it's checked below only if present
+    let validation = Validation {
+        validate_exp: false,
+        ..Validation::new(Algorithm::HS256)
+    };
+
+    let claims = match decode::<Claims>(token, &key, &validation) {
+        Ok(data) => data.claims,
+        Err(e) => {
+            debug!("reject jwt token: {:?}", e);
+            return None;
+        }
+    };
+
+    match claims.exp {
+        Some(exp) if now_secs().map(|now| exp <= now).unwrap_or(true) => {
+            debug!("reject jwt token: expired at {}", exp);
+            None
+        }
+        _ => Some(claims),
+    }
+}
+
+/// entry of token subcommand, print a new token to stdout
+pub fn token_main(config: &Config, subject: Option<&str>, scopes: Vec<&str>, ttl_secs: u64) -> io::Result<()> {
+    let secret = config
+        .jwt_secret
+        .as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "jwt_secret is not configured"))?;
+
+    let scopes = scopes
+        .into_iter()
+        .map(|x| serde_json::from_value(json!(x)).map_err(|_| format!("unknown scope {}", x)))
+        .collect::<Result<Vec<Scope>, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let token = mint(secret, subject, scopes, ttl_secs).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    println!("{}", token);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    const SECRET: &str = "test-secret";
+
+    fn sign(algorithm: Algorithm, claims: Value) -> String {
+        encode(
+            &Header::new(algorithm),
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn mint_and_verify() {
+        let token = mint(SECRET, Some("worker-1"), vec![Scope::JobsRead], 60).unwrap();
+        let claims = verify(SECRET, &token).unwrap();
+
+        assert_eq!(claims.scopes, vec![Scope::JobsRead]);
+        assert_eq!(claims.sub.as_deref(), Some("worker-1"));
+        assert!(claims.exp.is_some());
+    }
+
+    #[test]
+    fn bad_signature() {
+        let token = mint(SECRET, None, vec![Scope::Admin], 60).unwrap();
+        assert!(verify("other-secret", &token).is_none());
+
+        // claims changed after signing
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = sign(Algorithm::HS256, json!({"scopes": ["admin"], "sub": "forged"}));
+        let forged_claims = forged.split('.').nth(1).unwrap();
+        assert!(verify(SECRET, &format!("{}.{}.{}", parts[0], forged_claims, parts[2])).is_none());
+    }
+
+    #[test]
+    fn expired_token() {
+        let token = sign(
+            Algorithm::HS256,
+            json!({"exp": now_secs().unwrap() - 1, "scopes": ["admin"]}),
+        );
+        assert!(verify(SECRET, &token).is_none());
+    }
+
+    #[test]
+    fn token_without_exp_never_expires() {
+        let token = sign(Algorithm::HS256, json!({"scopes": ["jobs:read"]}));
+        assert_eq!(verify(SECRET, &token).unwrap().scopes, vec![Scope::JobsRead]);
+    }
+
+    #[test]
+    fn lotus_permissions() {
+        let token = sign(Algorithm::HS256, json!({"Allow": ["read", "write", "sign", "unknown"]}));
+        assert_eq!(
+            verify(SECRET, &token).unwrap().scopes,
+            vec![
+                Scope::JobsRead,
+                Scope::SealSubmit,
+                Scope::PostGenerate,
+                Scope::JobsWrite
+            ]
+        );
+
+        let token = sign(Algorithm::HS256, json!({"Allow": ["admin"]}));
+        assert_eq!(verify(SECRET, &token).unwrap().scopes, vec![Scope::Admin]);
+    }
+
+    #[test]
+    fn only_hs256() {
+        for algorithm in &[Algorithm::HS384, Algorithm::HS512] {
+            let token = sign(*algorithm, json!({"scopes": ["admin"]}));
+            assert!(verify(SECRET, &token).is_none(), "{:?} accepted", algorithm);
+        }
+    }
+
+    #[test]
+    fn base64_secret() {
+        let secret = "base64:dGVzdC1zZWNyZXQ=";
+        let token = sign(Algorithm::HS256, json!({"scopes": ["admin"]}));
+        assert_eq!(verify(secret, &token).unwrap().scopes, vec![Scope::Admin]);
+    }
+}
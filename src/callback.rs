@@ -0,0 +1,78 @@
+use crate::polling::PollingState;
+use crate::store::CallbackStatus;
+use crate::registry::ServState;
+use actix_rt::time::delay_for;
+use actix_web::client::Client;
+use hmac::{Hmac, Mac, NewMac};
+use log::*;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(30);
+/// delay before first retry, doubled after every failed attempt
+const CALLBACK_BACKOFF: Duration = Duration::from_secs(2);
+
+/// final state of a job to be posted to `url`
+#[derive(Debug)]
+pub struct Callback {
+    pub token: u64,
+    pub url: String,
+    pub secret: Option<String>,
+    pub state: PollingState,
+    pub max_attempts: u32,
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+
+    let sig: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect();
+
+    format!("sha256={}", sig)
+}
+
+/// post job state to callback url, retry with exponential backoff until `max_attempts`
+pub async fn deliver(state: Arc<ServState>, callback: Callback) {
+    let body = serde_json::to_vec(&callback.state).unwrap_or_default();
+    let client = Client::builder().timeout(CALLBACK_TIMEOUT).finish();
+    let mut backoff = CALLBACK_BACKOFF;
+    let mut attempts = 0;
+
+    let status = loop {
+        attempts += 1;
+
+        let mut req = client
+            .post(&callback.url)
+            .content_type("application/json")
+            .header("X-Webapi-Job", callback.token.to_string());
+        if let Some(secret) = &callback.secret {
+            req = req.header("X-Webapi-Signature", sign(secret, &body));
+        }
+
+        let error = match req.send_body(body.clone()).await {
+            Ok(resp) if resp.status().is_success() => break CallbackStatus::Delivered { attempts },
+            Ok(resp) => format!("callback responded {}", resp.status()),
+            Err(e) => format!("{:?}", e),
+        };
+
+        warn!(
+            "Job {} callback {} attempt {} failed: {}",
+            callback.token, callback.url, attempts, error
+        );
+        if attempts >= callback.max_attempts {
+            break CallbackStatus::Failed { attempts, error };
+        }
+
+        delay_for(backoff).await;
+        backoff *= 2;
+    };
+
+    debug!("Job {} callback finished: {:?}", callback.token, status);
+    state.set_callback_status(callback.token, status);
+}
@@ -1,15 +1,262 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Add;
 
+/// permission of an api token, `Admin` allows everything
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Scope {
+    #[serde(rename = "seal:submit")]
+    SealSubmit,
+    #[serde(rename = "post:generate")]
+    PostGenerate,
+    #[serde(rename = "jobs:read")]
+    JobsRead,
+    #[serde(rename = "jobs:write")]
+    JobsWrite,
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Scope::SealSubmit => "seal:submit",
+            Scope::PostGenerate => "post:generate",
+            Scope::JobsRead => "jobs:read",
+            Scope::JobsWrite => "jobs:write",
+            Scope::Admin => "admin",
+        };
+
+        f.write_str(name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TokenConfig {
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// scopes granted to client certificates with subject common name `subject`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientCertConfig {
+    pub subject: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// machine resources, either a budget or what a job takes
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Resources {
+    pub memory_gib: u64,
+    pub cpus: u64,
+    pub gpus: u64,
+}
+
+impl Resources {
+    pub fn fits_in(&self, budget: &Resources) -> bool {
+        self.memory_gib <= budget.memory_gib && self.cpus <= budget.cpus && self.gpus <= budget.gpus
+    }
+}
+
+impl Add for Resources {
+    type Output = Resources;
+
+    fn add(self, other: Resources) -> Resources {
+        Resources {
+            memory_gib: self.memory_gib + other.memory_gib,
+            cpus: self.cpus + other.cpus,
+            gpus: self.gpus + other.gpus,
+        }
+    }
+}
+
+/// retry failed jobs of a kind, only errors and crashes matching `retry_errors` or
+/// `retry_signals` are retried
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryPolicy {
+    /// total runs including the first one
+    pub max_attempts: u32,
+    /// delay before first retry, doubled after every failed attempt
+    #[serde(default = "default_retry_backoff_secs")]
+    pub backoff_secs: u64,
+    /// error or stderr of a crashed worker is transient if it contains any of these
+    #[serde(default = "default_retry_errors")]
+    pub retry_errors: Vec<String>,
+    /// worker killed by these signals is retried, SIGKILL is left out by default
+    /// since it's mostly sent by the oom killer and would happen again
+    #[serde(default = "default_retry_signals")]
+    pub retry_signals: Vec<i32>,
+}
+
+fn default_retry_backoff_secs() -> u64 {
+    10
+}
+
+fn default_retry_errors() -> Vec<String> {
+    vec!["os error".to_string(), "Os { code".to_string()]
+}
+
+fn default_retry_signals() -> Vec<i32> {
+    // SIGBUS, e.g. a mmapped file on nfs became unavailable
+    vec![7]
+}
+
+/// footprint of a job kind, `sector_size` in bytes matches any size if absent
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JobResources {
+    pub kind: String,
+    pub sector_size: Option<u64>,
+    #[serde(flatten)]
+    pub resources: Resources,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     #[serde(default)]
     pub auth: bool,
     pub listen_addr: String,
+    /// number of http worker threads, one per cpu core if unset
+    #[serde(default)]
+    pub http_workers: Option<usize>,
     pub private_cert: Option<String>,
     pub cert_chain: Option<String>,
+    /// pem bundle of CAs signing client certificates, clients must present a certificate if set
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    /// client certificates allowed by `auth`
+    #[serde(default)]
+    pub client_certs: Vec<ClientCertConfig>,
     #[serde(default)]
     pub job_limits: HashMap<String, u64>,
+    /// max queued jobs per kind when `job_limits` is reached, unlimited if absent
+    #[serde(default)]
+    pub job_queue_limits: HashMap<String, u64>,
+    /// seconds a job may run before it's killed, no limit if absent
+    #[serde(default)]
+    pub job_timeouts: HashMap<String, u64>,
+    /// failed jobs are not retried if absent
+    #[serde(default)]
+    pub job_retries: HashMap<String, RetryPolicy>,
+    /// total resources for jobs, jobs are only limited by `job_limits` if unset
+    #[serde(default)]
+    pub resources: Option<Resources>,
+    /// jobs not listed here take no resources
+    #[serde(default)]
+    pub job_resources: Vec<JobResources>,
+    /// tokens with full access
     #[serde(default)]
     pub allow_tokens: Vec<String>,
+    /// tokens limited to given scopes
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    /// secret to sign and verify HS256 jwt tokens, jwt tokens are rejected if unset.
+    /// a binary secret is given in base64 with prefix `base64:`
+    #[serde(default)]
+    pub jwt_secret: Option<String>,
+    /// file path parameters must be inside one of these dirs, paths are not checked if empty
+    #[serde(default)]
+    pub storage_roots: Vec<String>,
+    /// uploaded files are saved here, it's a storage root too so uploads can be job inputs
+    #[serde(default = "default_upload_dir")]
+    pub upload_dir: String,
+    /// directory to persist job records, jobs are kept in memory only if unset
+    #[serde(default)]
+    pub state_dir: Option<String>,
+    /// how long finished results are kept if never acknowledged
+    #[serde(default = "default_result_ttl_secs")]
+    pub result_ttl_secs: u64,
+    /// how long running jobs may take to finish after drain is requested
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    /// attempts to deliver a job callback before giving up
+    #[serde(default = "default_callback_max_attempts")]
+    pub callback_max_attempts: u32,
+}
+
+/// printed in place of secrets, config is logged at startup and shown by `debug_info`
+#[derive(Clone, Copy)]
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl fmt::Debug for TokenConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenConfig")
+            .field("token", &Redacted)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("auth", &self.auth)
+            .field("listen_addr", &self.listen_addr)
+            .field("http_workers", &self.http_workers)
+            .field("private_cert", &self.private_cert)
+            .field("cert_chain", &self.cert_chain)
+            .field("client_ca", &self.client_ca)
+            .field("client_certs", &self.client_certs)
+            .field("job_limits", &self.job_limits)
+            .field("job_queue_limits", &self.job_queue_limits)
+            .field("job_timeouts", &self.job_timeouts)
+            .field("job_retries", &self.job_retries)
+            .field("resources", &self.resources)
+            .field("job_resources", &self.job_resources)
+            .field("allow_tokens", &vec![Redacted; self.allow_tokens.len()])
+            .field("tokens", &self.tokens)
+            .field("jwt_secret", &self.jwt_secret.as_ref().map(|_| Redacted))
+            .field("storage_roots", &self.storage_roots)
+            .field("upload_dir", &self.upload_dir)
+            .field("state_dir", &self.state_dir)
+            .field("result_ttl_secs", &self.result_ttl_secs)
+            .field("drain_timeout_secs", &self.drain_timeout_secs)
+            .field("callback_max_attempts", &self.callback_max_attempts)
+            .finish()
+    }
+}
+
+fn default_upload_dir() -> String {
+    "/tmp/upload".to_string()
+}
+
+fn default_result_ttl_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    600
+}
+
+fn default_callback_max_attempts() -> u32 {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let config: Config = serde_yaml::from_str(
+            "listen_addr: 127.0.0.1:8888\n\
+             allow_tokens: [allow-secret]\n\
+             tokens: [{token: token-secret, scopes: [admin]}]\n\
+             jwt_secret: jwt-secret\n",
+        )
+        .unwrap();
+        let debug = format!("{:?}", config);
+
+        assert!(debug.contains("127.0.0.1:8888"));
+        for secret in &["allow-secret", "token-secret", "jwt-secret"] {
+            assert!(!debug.contains(secret), "{} leaked: {}", secret, debug);
+        }
+    }
 }
@@ -0,0 +1,73 @@
+use crate::callback;
+use crate::registry::ServState;
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_rt::time::delay_for;
+use actix_web::dev::Server;
+use futures::future;
+use log::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// interval to check running jobs while draining
+const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// background task to keep job states up to date
+pub async fn maintain(state: Arc<ServState>) {
+    loop {
+        delay_for(Duration::from_secs(1)).await;
+
+        state.update();
+        for cb in state.take_callbacks() {
+            actix_rt::spawn(callback::deliver(state.clone(), cb));
+        }
+    }
+}
+
+/// stop server once drain is requested, running jobs get `drain_timeout_secs` to finish
+pub async fn shutdown(state: Arc<ServState>, server: Server) {
+    while !state.is_draining() {
+        delay_for(Duration::from_secs(1)).await;
+    }
+
+    // results of finished jobs are persisted by `maintain` meanwhile
+    let deadline = Instant::now() + state.drain_timeout();
+    loop {
+        let status = state.drain_status();
+        if status.running == 0 {
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            warn!("drain timeout, interrupt {} running jobs", status.running);
+            state.interrupt_running();
+            break;
+        }
+
+        info!(
+            "draining, {} jobs running, {} jobs queued",
+            status.running, status.queued
+        );
+        delay_for(DRAIN_INTERVAL).await;
+    }
+
+    info!("drained, stopping server");
+    server.stop(true).await;
+}
+
+/// SIGTERM or SIGINT starts draining, a second one interrupts running jobs at once
+pub async fn handle_signals(state: Arc<ServState>) {
+    let mut term = signal(SignalKind::terminate()).expect("listen SIGTERM failed");
+    let mut int = signal(SignalKind::interrupt()).expect("listen SIGINT failed");
+
+    loop {
+        future::select(Box::pin(term.recv()), Box::pin(int.recv())).await;
+
+        if state.start_drain() {
+            warn!("signal received, draining");
+        } else {
+            warn!("signal received again, exit now");
+            state.interrupt_running();
+            std::process::exit(1);
+        }
+    }
+}
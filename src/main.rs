@@ -1,4 +1,4 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 // use actix_web::FromRequest;
 use actix_web::{error, middleware, web};
 use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
@@ -6,21 +6,28 @@ use log::*;
 // use crate::seal_data::SealCommitPhase2Data;
 use crate::config::Config;
 use crate::mid::verify::Verify;
-use crate::system::ServState;
+use crate::registry::ServState;
 use actix_web::middleware::Condition;
-use clap::Arg;
-//use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use clap::{AppSettings, Arg, SubCommand};
 use std::fs::metadata;
 
+mod auth;
+mod callback;
 mod config;
+mod drain;
 mod mid;
 mod polling;
 pub mod post;
 pub mod post_data;
+mod registry;
+mod sandbox;
 pub mod seal;
 pub mod seal_data;
+mod store;
 mod system;
+mod tls;
 mod types;
+mod worker;
 
 #[allow(dead_code)]
 fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> error::Error {
@@ -51,6 +58,45 @@ async fn main() -> std::io::Result<()> {
                 .required(true)
                 .default_value("/etc/filecoin-webapi.conf"),
         )
+        .subcommand(
+            SubCommand::with_name(worker::WORKER_COMMAND)
+                .about("run a single job, spawned by server")
+                .setting(AppSettings::Hidden)
+                .arg(Arg::with_name("kind").long("--kind").takes_value(true).required(true))
+                .arg(Arg::with_name("input").long("--input").takes_value(true).required(true))
+                .arg(
+                    Arg::with_name("output")
+                        .long("--output")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(auth::TOKEN_COMMAND)
+                .about("mint a jwt token signed with jwt_secret")
+                .arg(
+                    Arg::with_name("scope")
+                        .long("--scope")
+                        .help("Scope granted to token, can be repeated")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("subject")
+                        .long("--subject")
+                        .help("Client the token is issued to, tokens of the same subject share jobs")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("--ttl")
+                        .help("Seconds before token expires")
+                        .takes_value(true)
+                        .default_value("3600"),
+                ),
+        )
         .get_matches();
 
     // default logger settings
@@ -64,6 +110,14 @@ async fn main() -> std::io::Result<()> {
 
     env_logger::init();
 
+    if let Some(m) = m.subcommand_matches(worker::WORKER_COMMAND) {
+        return worker::worker_main(
+            m.value_of("kind").unwrap(),
+            m.value_of("input").unwrap(),
+            m.value_of("output").unwrap(),
+        );
+    }
+
     // if TMPDIR is set, ensure dir is exist
     if let Ok(tmp) = std::env::var("TMPDIR") {
         match metadata(&tmp).map(|x| x.is_dir()) {
@@ -77,31 +131,52 @@ async fn main() -> std::io::Result<()> {
         warn!("CUDA_VISIBLE_DEVICES={}", cuda_devs);
     }
 
-    // create upload dir
-    std::fs::create_dir_all("/tmp/upload/")?;
-
     let config_file = m.value_of("config").unwrap();
     let f = std::fs::File::open(config_file).unwrap();
     let config: Config = serde_yaml::from_reader(f).unwrap();
+
+    if let Some(m) = m.subcommand_matches(auth::TOKEN_COMMAND) {
+        let ttl = m
+            .value_of("ttl")
+            .unwrap()
+            .parse()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+        return auth::token_main(
+            &config,
+            m.value_of("subject"),
+            m.values_of("scope").unwrap().collect(),
+            ttl,
+        );
+    }
+
     info!("config {:?}", config);
 
-    let state = Arc::new(Mutex::new(ServState::new(config.clone())));
+    // create upload dir
+    std::fs::create_dir_all(&config.upload_dir)?;
+
+    let state = Arc::new(ServState::new(config.clone()));
+    actix_rt::spawn(drain::maintain(state.clone()));
+
     let bind_addr = config.listen_addr.clone();
     let auth = config.auth;
-    //let private_cert = config.private_cert.clone();
-    //let cert_chain = config.cert_chain.clone();
+    let app_state = state.clone();
     let server = HttpServer::new(move || {
-        let state = state.clone();
+        let state = app_state.clone();
 
         App::new()
             .wrap(middleware::Logger::default())
             .wrap(Condition::new(auth, Verify {}))
-            .app_data(web::Data::new(state))
+            .app_data(web::Data::from(state))
             .service(web::resource("/test").route(web::get().to(system::test)))
             .service(web::resource("/sys/test_polling").route(web::post().to(system::test_polling)))
             .service(web::resource("/sys/query_state").route(web::post().to(system::query_state)))
+            .service(web::resource("/sys/watch/{token}").route(web::get().to(system::watch)))
             .service(web::resource("/sys/debug_info").route(web::post().to(system::debug_info)))
+            .service(web::resource("/sys/query_group").route(web::post().to(system::query_group)))
+            .service(web::resource("/sys/list_jobs").route(web::post().to(system::list_jobs)))
+            .service(web::resource("/sys/ack_job").route(web::post().to(system::ack_job)))
             .service(web::resource("/sys/remove_job").route(web::post().to(system::remove_job)))
+            .service(web::resource("/sys/drain").route(web::post().to(system::drain)))
             .service(web::resource("/sys/upload_file").route(web::post().to(system::upload_file)))
             .service(web::resource("/sys/upload_test").route(web::get().to(system::upload_test)))
             .service(
@@ -111,6 +186,9 @@ async fn main() -> std::io::Result<()> {
             .service(web::resource("/post/generate_winning_post").route(web::post().to(post::generate_winning_post)))
             .service(web::resource("/post/verify_winning_post").route(web::post().to(post::verify_winning_post)))
             .service(web::resource("/post/generate_window_post").route(web::post().to(post::generate_window_post)))
+            .service(
+                web::resource("/post/generate_window_post_job").route(web::post().to(post::generate_window_post_job)),
+            )
             .service(web::resource("/post/verify_window_post").route(web::post().to(post::verify_window_post)))
             .service(web::resource("/seal/clear_cache").route(web::post().to(seal::clear_cache)))
             .service(web::resource("/seal/seal_pre_commit_phase1").route(web::post().to(seal::seal_pre_commit_phase1)))
@@ -126,6 +204,9 @@ async fn main() -> std::io::Result<()> {
                     // }))
                     .route(web::post().to(seal::seal_commit_phase2)),
             )
+            .service(
+                web::resource("/seal/seal_commit_phase2_batch").route(web::post().to(seal::seal_commit_phase2_batch)),
+            )
             .service(web::resource("/seal/verify_seal").route(web::post().to(seal::verify_seal)))
             .service(web::resource("/seal/verify_batch_seal").route(web::post().to(seal::verify_batch_seal)))
             .service(web::resource("/seal/get_unsealed_range").route(web::post().to(seal::get_unsealed_range)))
@@ -134,24 +215,55 @@ async fn main() -> std::io::Result<()> {
             )
             .service(web::resource("/seal/add_piece").route(web::post().to(seal::add_piece)))
             .service(web::resource("/seal/write_and_preprocess").route(web::post().to(seal::write_and_preprocess)))
-    });
-
-    // let server = if let Some(cert) = private_cert {
-    //     warn!("use private-cert file {}", cert);
-
-    //     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-    //     builder.set_private_key_file(cert, SslFiletype::PEM).unwrap();
-    //     if let Some(chain) = cert_chain {
-    //         warn!("use cert-chain file {}", chain);
-    //         builder.set_certificate_chain_file(chain).unwrap();
-    //     }
-
-    //     server.bind_openssl(bind_addr, builder)
-    // } else {
-    //  server.bind(bind_addr)
-    // };
+    })
+    .on_connect(tls::on_connect);
+
+    // invalid cert files are reported before binding, so server never serves plain http by mistake
+    let tls = match (&config.private_cert, &config.cert_chain) {
+        (Some(private_cert), Some(cert_chain)) => {
+            warn!("use private-cert file {}, cert-chain file {}", private_cert, cert_chain);
+            let resolver = tls::CertResolver::new(private_cert, cert_chain).map_err(|e| {
+                error!("invalid tls certificate: {}", e);
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid tls certificate: {}", e),
+                )
+            })?;
+            Some(Arc::new(resolver))
+        }
+        (None, None) if config.client_ca.is_none() => None,
+        (None, None) => {
+            let e = "client_ca requires private_cert and cert_chain";
+            error!("{}", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+        }
+        _ => {
+            let e = "private_cert and cert_chain must be set together";
+            error!("{}", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+        }
+    };
     
-    let server = server.bind(bind_addr);
+    // signals are handled by drain mode, so running jobs can finish first
+    let mut server = match tls {
+        Some(resolver) => {
+            actix_rt::spawn(tls::watch(resolver.clone()));
+            let tls_config = tls::server_config(resolver, config.client_ca.as_deref()).map_err(|e| {
+                error!("invalid client ca: {}", e);
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid client ca: {}", e))
+            })?;
+            server.bind_rustls(bind_addr, tls_config)?
+        }
+        None => server.bind(bind_addr)?,
+    }
+    .disable_signals();
+    if let Some(workers) = config.http_workers {
+        server = server.workers(workers);
+    }
+
+    let server = server.run();
+    actix_rt::spawn(drain::handle_signals(state.clone()));
+    actix_rt::spawn(drain::shutdown(state, server.clone()));
 
-    server?.workers(1).run().await
+    server.await
 }
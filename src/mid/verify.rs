@@ -1,10 +1,26 @@
-use crate::system::ServState;
+use crate::config::Scope;
+use crate::registry::ServState;
+use crate::tls::ClientSubject;
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::web::Data;
+use actix_web::HttpMessage;
 use actix_web::{Error, HttpResponse};
 use futures::future::{ok, Either, Ready};
 use futures::task::{Context, Poll};
-use std::sync::{Arc, Mutex};
+
+/// scope needed to call `path`, `None` if path is public
+fn required_scope(path: &str) -> Option<Scope> {
+    match path {
+        "/test" => None,
+        "/sys/query_state" | "/sys/list_jobs" | "/sys/query_group" => Some(Scope::JobsRead),
+        p if p.starts_with("/sys/watch/") => Some(Scope::JobsRead),
+        "/sys/ack_job" | "/sys/remove_job" | "/sys/test_polling" => Some(Scope::JobsWrite),
+        "/sys/upload_file" | "/sys/upload_test" => Some(Scope::SealSubmit),
+        p if p.starts_with("/seal/") => Some(Scope::SealSubmit),
+        p if p.starts_with("/post/") => Some(Scope::PostGenerate),
+        _ => Some(Scope::Admin),
+    }
+}
 
 pub struct Verify;
 
@@ -44,21 +60,70 @@ where
     }
 
     fn call(&mut self, req: Self::Request) -> Self::Future {
-        let allow = "/test";
-        let path = req.path();
-        let data = req.app_data::<Data<Arc<Mutex<ServState>>>>().unwrap();
-
-        let token = req
-            .headers()
-            .get("Authorization")
-            .and_then(|x| x.to_str().ok())
-            .map(|x| data.lock().unwrap().verify_token(x))
-            .unwrap_or(false);
-
-        if token || path == allow {
-            Either::Left(self.service.call(req))
-        } else {
-            Either::Right(ok(req.into_response(HttpResponse::Forbidden().finish().into_body())))
+        let scope = match required_scope(req.path()) {
+            Some(scope) => scope,
+            None => return Either::Left(self.service.call(req)),
+        };
+
+        let data = req.app_data::<Data<ServState>>().unwrap();
+        // clients without a token are identified by their certificate
+        let scopes = match req.headers().get("Authorization") {
+            Some(token) => token.to_str().ok().and_then(|x| data.token_scopes(x)),
+            None => req
+                .extensions()
+                .get::<ClientSubject>()
+                .and_then(|x| data.client_scopes(&x.0)),
+        };
+
+        let denied = match scopes {
+            None => Some("invalid or missing token or client certificate".to_string()),
+            Some(scopes) if scopes.contains(&Scope::Admin) || scopes.contains(&scope) => None,
+            Some(_) => Some(format!("token lacks scope `{}` required by {}", scope, req.path())),
+        };
+
+        match denied {
+            None => Either::Left(self.service.call(req)),
+            Some(reason) => {
+                let response = HttpResponse::Forbidden().content_type("text/plain").body(reason);
+                Either::Right(ok(req.into_response(response.into_body())))
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_management_needs_jobs_write() {
+        assert_eq!(required_scope("/sys/remove_job"), Some(Scope::JobsWrite));
+        assert_eq!(required_scope("/sys/ack_job"), Some(Scope::JobsWrite));
+    }
+
+    #[test]
+    fn job_queries_need_jobs_read() {
+        assert_eq!(required_scope("/sys/query_state"), Some(Scope::JobsRead));
+        assert_eq!(required_scope("/sys/watch/42"), Some(Scope::JobsRead));
+    }
+
+    #[test]
+    fn seal_and_post_apis() {
+        assert_eq!(required_scope("/seal/clear_cache"), Some(Scope::SealSubmit));
+        assert_eq!(required_scope("/seal/seal_commit_phase2"), Some(Scope::SealSubmit));
+        assert_eq!(required_scope("/post/generate_window_post"), Some(Scope::PostGenerate));
+    }
+
+    #[test]
+    fn admin_apis() {
+        assert_eq!(required_scope("/sys/debug_info"), Some(Scope::Admin));
+        assert_eq!(required_scope("/sys/drain"), Some(Scope::Admin));
+        // unknown paths are never public
+        assert_eq!(required_scope("/sys/unknown"), Some(Scope::Admin));
+    }
+
+    #[test]
+    fn public_apis() {
+        assert_eq!(required_scope("/test"), None);
+    }
+}
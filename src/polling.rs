@@ -1,17 +1,87 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::time::SystemTime;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PollingState {
     Started(u64),
-    Pending,
+    Queued { position: u64 },
+    Pending(JobProgress),
     Done(Value),
     Removed,
+    Cancelled,
+    Interrupted,
     Error(PollingError),
 }
 
+impl PollingState {
+    /// job will never change state again
+    pub fn is_finished(&self) -> bool {
+        !matches!(
+            self,
+            PollingState::Started(_) | PollingState::Queued { .. } | PollingState::Pending(_)
+        )
+    }
+
+    /// whether state moved to another stage, progress updates are not counted
+    pub fn changed(&self, other: &PollingState) -> bool {
+        match (self, other) {
+            (PollingState::Queued { position: x }, PollingState::Queued { position: y }) => x != y,
+            _ => std::mem::discriminant(self) != std::mem::discriminant(other),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PollingError {
     NotExist,
     Disconnected,
+    /// worker process was killed by `signal` or exited abnormally
+    Crashed {
+        signal: Option<i32>,
+        stderr_tail: String,
+    },
+    /// job can not finish before `deadline`, estimated by past durations
+    DeadlineExceeded {
+        deadline: u64,
+        estimated_secs: Option<u64>,
+    },
+    /// job was killed after running `after_secs` seconds
+    Timeout {
+        after_secs: u64,
+    },
+    /// job `token` whose output is input of this job did not succeed
+    DependencyFailed {
+        token: u64,
+        reason: String,
+    },
+    /// job queue of this kind is full, submit again later
+    QueueFull,
+    /// job needs more resources than the machine has in total
+    Unschedulable {
+        reason: String,
+    },
+    /// idempotency key was already used by job `token` for a different request
+    IdempotencyKeyReused {
+        token: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobProgress {
+    pub phase: String,
+    pub started_at: Option<SystemTime>,
+    pub elapsed_secs: u64,
+    /// estimated from past durations of same job kind and sector size
+    pub estimated_remaining_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_attempts: Vec<JobAttempt>,
+}
+
+/// a failed run of a job which was retried
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobAttempt {
+    pub started_at: Option<SystemTime>,
+    pub finished_at: SystemTime,
+    pub error: String,
 }
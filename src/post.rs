@@ -1,9 +1,11 @@
-use actix_web::web::Json;
-use actix_web::{HttpRequest, HttpResponse};
+use actix_web::web::{Data, Json};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
 use filecoin_proofs_api::post;
 use log::*;
+use serde_json::{json, Value};
 
 use crate::post_data::*;
+use crate::registry::{submit_job, ServState};
 
 pub async fn generate_winning_post_sector_challenge(
     _req: HttpRequest,
@@ -23,9 +25,18 @@ pub async fn generate_winning_post_sector_challenge(
     HttpResponse::Ok().json(response)
 }
 
-pub async fn generate_winning_post(_req: HttpRequest, data: Json<GenerateWinningPostData>) -> HttpResponse {
+pub async fn generate_winning_post(
+    _req: HttpRequest,
+    state: Data<ServState>,
+    data: Json<GenerateWinningPostData>,
+) -> HttpResponse {
     trace!("generate_winning_post: {:?}", data);
 
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+
     let r = post::generate_winning_post(&data.randomness, &data.replicas.as_object(), data.prover_id);
 
     let response = r.map_err(|e| format!("{:?}", e));
@@ -48,9 +59,18 @@ pub async fn verify_winning_post(_req: HttpRequest, data: Json<VerifyWinningPost
     HttpResponse::Ok().json(response)
 }
 
-pub async fn generate_window_post(_req: HttpRequest, data: Json<GenerateWindowPostData>) -> HttpResponse {
+pub async fn generate_window_post(
+    _req: HttpRequest,
+    state: Data<ServState>,
+    data: Json<GenerateWindowPostData>,
+) -> HttpResponse {
     trace!("generate_window_post: {:?}", data);
 
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+
     let r = post::generate_window_post(&data.randomness, &data.replicas.as_object(), data.prover_id);
 
     let response = r.map_err(|e| format!("{:?}", e));
@@ -58,6 +78,34 @@ pub async fn generate_window_post(_req: HttpRequest, data: Json<GenerateWindowPo
     HttpResponse::Ok().json(response)
 }
 
+/// body of window PoSt job, runs in worker process
+pub fn run_generate_window_post(data: GenerateWindowPostJobData) -> Value {
+    let r = post::generate_window_post(&data.randomness, &data.replicas.as_object(), data.prover_id);
+
+    trace!("generate_window_post finished: {:?}", r);
+    json!(r.map_err(|e| format!("{:?}", e)))
+}
+
+/// window PoSt as a job, scheduled before jobs without deadline
+pub async fn generate_window_post_job(
+    req: HttpRequest,
+    state: Data<ServState>,
+    data: Json<GenerateWindowPostJobData>,
+) -> HttpResponse {
+    trace!("generate_window_post_job: {:?}", data);
+
+    let mut data = data.into_inner();
+    if data.options.deadline.is_none() {
+        return HttpResponse::BadRequest().body("deadline is required");
+    }
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+
+    let options = std::mem::take(&mut data.options);
+    submit_job(&state, &req, "WindowPoSt", json!(data), options)
+}
+
 pub async fn verify_window_post(_req: HttpRequest, data: Json<VerifyWindowPostData>) -> HttpResponse {
     trace!("verify_window_post: {:?}", data);
 
@@ -28,6 +28,15 @@ pub struct VerifyWinningPostData {
 
 pub type GenerateWindowPostData = GenerateWinningPostData;
 
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct GenerateWindowPostJobData {
+    pub randomness: ChallengeSeed,
+    pub replicas: WebPrivateReplicas,
+    pub prover_id: ProverId,
+    #[serde(flatten)]
+    pub options: JobOptions,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct VerifyWindowPostData {
     pub randomness: ChallengeSeed,
@@ -0,0 +1,1666 @@
+use crate::auth;
+use crate::callback::Callback;
+use crate::config::{Config, Resources, RetryPolicy, Scope};
+use crate::polling::*;
+use crate::sandbox::Sandbox;
+use crate::store::{CallbackStatus, JobDependency, JobInfo, JobRecord, JobStatus, JobStore};
+use crate::tls::ClientSubject;
+use crate::types::JobOptions;
+use crate::worker::{job_sector, job_sector_size, open_work_dir, JobProcess, INVALID_INPUT};
+use actix_web::web::Data;
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// how many past durations are kept for each job kind and sector size
+const HISTORY_LEN: usize = 20;
+/// clients are asked to come back after this long while server is draining
+const DRAIN_RETRY_AFTER_SECS: u64 = 60;
+
+lazy_static! {
+    static ref WORKER_TOKEN: AtomicU64 = AtomicU64::new(0);
+    static ref WORKER_INIT: AtomicBool = AtomicBool::new(false);
+}
+
+pub struct WorkerProp {
+    name: String,
+    digest: String,
+    sector_size: Option<u64>,
+    sector_id: Option<u64>,
+    prover_id: Option<String>,
+    owner: Option<String>,
+    idempotency_key: Option<String>,
+    options: JobOptions,
+    callback: Option<CallbackStatus>,
+    // resources reserved while the job is running
+    footprint: Resources,
+    retry: Option<RetryPolicy>,
+    failed_attempts: Vec<JobAttempt>,
+    // a failed job waits in queue until this time before next attempt
+    retry_at: Option<SystemTime>,
+    // job waits in queue until output of dependency is available
+    dependency: Option<JobDependency>,
+    // job input, dropped once the job is finished
+    input: Option<Arc<Value>>,
+    process: Option<JobProcess>,
+    // worker process is being spawned outside of locks, the job counts as running
+    launching: bool,
+    result: Option<Value>,
+    error: Option<PollingError>,
+    cancelled: bool,
+    // killed by server shutdown
+    interrupted: bool,
+    create_time: SystemTime,
+    start_time: Option<SystemTime>,
+    last_query: SystemTime,
+    finish_time: Option<SystemTime>,
+}
+
+impl fmt::Debug for WorkerProp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {:#?}", self.name)?;
+        writeln!(f, "digest: {}", self.digest)?;
+        writeln!(f, "sector_size: {:?}", self.sector_size)?;
+        writeln!(f, "sector_id: {:?}", self.sector_id)?;
+        writeln!(f, "owner: {:?}", self.owner)?;
+        writeln!(f, "idempotency_key: {:?}", self.idempotency_key)?;
+        writeln!(f, "footprint: {:?}", self.footprint)?;
+        writeln!(f, "failed_attempts: {:?}", self.failed_attempts)?;
+        writeln!(f, "retry_at: {:?}", self.retry_at)?;
+        writeln!(f, "dependency: {:?}", self.dependency)?;
+        writeln!(f, "pid: {:?}", self.process.as_ref().map(|x| x.pid()))?;
+        writeln!(f, "launching: {}", self.launching)?;
+        writeln!(f, "queued: {}", self.is_queued())?;
+        writeln!(f, "finished: {}", self.is_finished())?;
+        writeln!(f, "error: {:?}", self.error)?;
+        writeln!(f, "callback: {:?}", self.callback)?;
+        writeln!(f, "cancelled: {}", self.cancelled)?;
+        writeln!(f, "interrupted: {}", self.interrupted)?;
+        writeln!(f, "create_time: {:#?}", self.create_time)?;
+        writeln!(f, "start_time: {:#?}", self.start_time)?;
+        writeln!(f, "last_query: {:#?}", self.last_query)?;
+        writeln!(f, "since_query: {}", self.last_query_since_secs())?;
+        writeln!(
+            f,
+            "since_create: {:#?}",
+            SystemTime::now().duration_since(self.create_time).map(|x| x.as_secs())
+        )
+    }
+}
+
+impl WorkerProp {
+    pub fn new(name: String, input: Value) -> Self {
+        let (sector_id, prover_id) = job_sector(&input);
+
+        Self {
+            digest: input_digest(serde_json::to_vec(&input).unwrap_or_default()),
+            sector_size: job_sector_size(&input),
+            sector_id,
+            prover_id,
+            owner: None,
+            idempotency_key: None,
+            name,
+            options: JobOptions::default(),
+            callback: None,
+            footprint: Resources::default(),
+            retry: None,
+            failed_attempts: vec![],
+            retry_at: None,
+            dependency: None,
+            input: Some(Arc::new(input)),
+            process: None,
+            launching: false,
+            result: None,
+            error: None,
+            cancelled: false,
+            interrupted: false,
+            create_time: SystemTime::now(),
+            start_time: None,
+            last_query: SystemTime::now(),
+            finish_time: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: JobOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// take submitter and idempotency key from request headers
+    pub fn with_request(mut self, req: &HttpRequest) -> Self {
+        self.owner = request_owner(req);
+        self.idempotency_key = req
+            .headers()
+            .get("Idempotency-Key")
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_owned());
+        self
+    }
+
+    /// input `field` is filled with output of job `token` before the job starts
+    pub fn with_dependency(mut self, token: u64, field: &str) -> Self {
+        self.dependency = Some(JobDependency {
+            token,
+            field: field.to_string(),
+        });
+        self
+    }
+
+    fn resolve_dependency(&mut self, output: Value) {
+        if let (Some(dependency), Some(input)) = (self.dependency.take(), self.input.as_mut()) {
+            Arc::make_mut(input)[dependency.field.as_str()] = output;
+            self.sector_size = job_sector_size(input);
+        }
+    }
+
+    /// item of a batch, idempotency key is made unique per item
+    pub fn with_batch_index(mut self, index: usize) -> Self {
+        if let Some(key) = &self.idempotency_key {
+            self.idempotency_key = Some(format!("{}/{}", key, index));
+        }
+        self
+    }
+
+    /// whether this job is a resubmission of a job with given properties
+    fn same_job(&self, kind: &str, digest: &str, owner: &Option<String>, key: &Option<String>) -> bool {
+        if self.name != kind || &self.owner != owner {
+            return false;
+        }
+
+        match &self.idempotency_key {
+            Some(_) => &self.idempotency_key == key,
+            None => self.digest == digest,
+        }
+    }
+
+    /// whether idempotency key of this job was used by a job with given properties
+    /// for a different request
+    fn key_reused(&self, kind: &str, digest: &str, owner: &Option<String>, key: &Option<String>) -> bool {
+        self.idempotency_key.is_some()
+            && &self.idempotency_key == key
+            && &self.owner == owner
+            && (self.name != kind || self.digest != digest)
+    }
+
+    fn is_finished(&self) -> bool {
+        self.result.is_some() || self.error.is_some() || self.cancelled || self.interrupted
+    }
+
+    fn is_queued(&self) -> bool {
+        self.process.is_none() && !self.launching && !self.is_finished()
+    }
+
+    fn is_running(&self) -> bool {
+        (self.process.is_some() || self.launching) && !self.is_finished()
+    }
+
+    /// mark a queued job as started, the returned input is handed to `launched`
+    /// once its worker is spawned
+    fn start(&mut self, token: u64) -> Option<Arc<Value>> {
+        let input = match &self.input {
+            Some(input) if self.is_queued() => input.clone(),
+            _ => return None,
+        };
+
+        debug!("Job {} {} started", token, self.name);
+        self.start_time = Some(SystemTime::now());
+        self.launching = true;
+
+        Some(input)
+    }
+
+    /// take the worker spawned for this job, it's killed if the job is stopped meanwhile
+    fn launched(&mut self, token: u64, r: io::Result<JobProcess>) {
+        self.launching = false;
+
+        match r {
+            Ok(mut process) if self.is_finished() => {
+                debug!("Job {} stopped while launching, kill worker {}", token, process.pid());
+                if let Err(e) = process.kill() {
+                    warn!("Job {} kill worker {} failed: {:?}", token, process.pid(), e);
+                }
+            }
+            Ok(process) => self.process = Some(process),
+            Err(_) if self.is_finished() => {}
+            Err(e) => {
+                error!("Job {} spawn worker failed: {:?}", token, e);
+                self.finish(Ok(json!(Err::<(), _>(format!("spawn worker failed: {:?}", e)))));
+            }
+        }
+    }
+
+    fn finish(&mut self, r: Result<Value, PollingError>) {
+        match r {
+            Ok(r) => self.result = Some(r),
+            Err(e) => self.error = Some(e),
+        }
+        self.input = None;
+        self.finish_time = Some(SystemTime::now());
+    }
+
+    /// check whether worker process exited, return true if the job is finished,
+    /// a job failed with transient error is put back to queue instead
+    fn try_finish(&mut self) -> bool {
+        if self.is_running() {
+            if let Some(r) = self.process.as_mut().and_then(|x| x.try_finish()) {
+                match self.retry_delay(&r) {
+                    Some(delay) => self.requeue(&r, delay),
+                    None => self.finish(r),
+                }
+            }
+        }
+
+        self.is_finished()
+    }
+
+    /// delay before next attempt if the job should be retried
+    fn retry_delay(&self, r: &Result<Value, PollingError>) -> Option<Duration> {
+        let policy = self.retry.as_ref()?;
+        let failed = self.failed_attempts.len() as u32 + 1;
+        if failed >= policy.max_attempts {
+            return None;
+        }
+
+        let matches = |e: &str| !e.starts_with(INVALID_INPUT) && policy.retry_errors.iter().any(|x| e.contains(x));
+        let transient = match r {
+            Err(PollingError::Crashed {
+                signal: Some(signal), ..
+            }) => policy.retry_signals.contains(signal),
+            Err(PollingError::Crashed { stderr_tail, .. }) => matches(stderr_tail),
+            Ok(r) => r.get("Err").and_then(|x| x.as_str()).map(matches).unwrap_or(false),
+            Err(_) => false,
+        };
+
+        if transient {
+            Some(Duration::from_secs(policy.backoff_secs << (failed - 1).min(16)))
+        } else {
+            None
+        }
+    }
+
+    fn requeue(&mut self, r: &Result<Value, PollingError>, delay: Duration) {
+        let error = match r {
+            Ok(r) => r
+                .get("Err")
+                .map(|x| x.as_str().map(|e| e.to_owned()).unwrap_or_else(|| x.to_string()))
+                .unwrap_or_default(),
+            Err(e) => format!("{:?}", e),
+        };
+
+        warn!(
+            "Job {} attempt {} failed, retry in {:?}: {}",
+            self.name,
+            self.failed_attempts.len() + 1,
+            delay,
+            error
+        );
+        self.failed_attempts.push(JobAttempt {
+            started_at: self.start_time,
+            finished_at: SystemTime::now(),
+            error,
+        });
+        self.process = None;
+        self.start_time = None;
+        self.retry_at = Some(SystemTime::now() + delay);
+    }
+
+    /// queued job is waiting for retry backoff
+    fn retry_pending(&self) -> bool {
+        self.retry_at.map(|x| x > SystemTime::now()).unwrap_or(false)
+    }
+
+    /// kill worker process if it's running, output files it wrote are removed or
+    /// truncated to their length before the job started
+    fn kill(&mut self, token: u64) {
+        if self.is_running() {
+            if let Some(process) = self.process.as_mut() {
+                if let Err(e) = process.kill() {
+                    warn!("Job {} kill worker {} failed: {:?}", token, process.pid(), e);
+                }
+            }
+        }
+    }
+
+    fn cancel(&mut self, token: u64) {
+        self.kill(token);
+        self.cancelled = true;
+        self.input = None;
+        self.finish_time = Some(SystemTime::now());
+    }
+
+    /// stop the job because server is shutting down
+    fn interrupt(&mut self, token: u64) {
+        self.kill(token);
+        self.interrupted = true;
+        self.input = None;
+        self.finish_time = Some(SystemTime::now());
+    }
+
+    /// stop the job with an error, e.g. it can not meet its deadline
+    fn fail(&mut self, token: u64, e: PollingError) {
+        self.kill(token);
+        self.finish(Err(e));
+    }
+
+    /// window posts start before any other kind of jobs, within the same rank jobs with
+    /// earlier deadline start first, then jobs without deadline in submit order
+    fn schedule_key(&self, token: u64) -> ScheduleKey {
+        let rank = if self.name == "WindowPoSt" { 0 } else { 1 };
+        let deadline = self.options.deadline;
+        (rank, deadline.is_none(), deadline.unwrap_or(0), token)
+    }
+
+    /// error if job is running longer than its timeout
+    fn check_timeout(&self, timeouts: &HashMap<String, u64>) -> Option<PollingError> {
+        let after_secs = self
+            .options
+            .timeout_secs
+            .or_else(|| timeouts.get(&self.name).copied())?;
+
+        match self.run_secs() {
+            Some(secs) if self.is_running() && secs >= after_secs => Some(PollingError::Timeout { after_secs }),
+            _ => None,
+        }
+    }
+
+    /// error if job can not finish before its deadline, a queued job is judged by
+    /// estimated duration while a running job only fails once the deadline passed
+    fn check_deadline(&self, history: &HashMap<String, Vec<u64>>) -> Option<PollingError> {
+        let deadline = self.options.deadline?;
+        let estimated_secs = self.progress(history).estimated_remaining_secs;
+        let remaining = if self.is_running() {
+            0
+        } else {
+            estimated_secs.unwrap_or(0)
+        };
+
+        if SystemTime::now() + Duration::from_secs(remaining) <= UNIX_EPOCH + Duration::from_secs(deadline) {
+            return None;
+        }
+
+        Some(PollingError::DeadlineExceeded {
+            deadline,
+            estimated_secs,
+        })
+    }
+
+    /// key of job duration history
+    fn history_key(&self) -> String {
+        format!("{}/{}", self.name, self.sector_size.unwrap_or(0))
+    }
+
+    fn run_secs(&self) -> Option<u64> {
+        let end = self.finish_time.unwrap_or_else(SystemTime::now);
+
+        self.start_time
+            .and_then(|x| end.duration_since(x).ok())
+            .map(|x| x.as_secs())
+    }
+
+    fn progress(&self, history: &HashMap<String, Vec<u64>>) -> JobProgress {
+        let elapsed_secs = self.run_secs().unwrap_or(0);
+        let estimated_remaining_secs = history
+            .get(&self.history_key())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.iter().sum::<u64>() / x.len() as u64)
+            .map(|x| x.saturating_sub(elapsed_secs));
+
+        JobProgress {
+            phase: self.name.clone(),
+            started_at: self.start_time,
+            elapsed_secs,
+            estimated_remaining_secs,
+            failed_attempts: self.failed_attempts.clone(),
+        }
+    }
+
+    fn state(&self, history: &HashMap<String, Vec<u64>>) -> PollingState {
+        if self.cancelled {
+            return PollingState::Cancelled;
+        }
+
+        if self.interrupted {
+            return PollingState::Interrupted;
+        }
+
+        if let Some(e) = &self.error {
+            return PollingState::Error(e.clone());
+        }
+
+        match &self.result {
+            Some(r) => PollingState::Done(r.clone()),
+            None => PollingState::Pending(self.progress(history)),
+        }
+    }
+
+    fn status(&self) -> JobStatus {
+        if self.cancelled {
+            JobStatus::Cancelled
+        } else if self.interrupted {
+            JobStatus::Interrupted
+        } else if self.error.is_some() {
+            JobStatus::Failed
+        } else if let Some(r) = &self.result {
+            JobStatus::of_result(r)
+        } else if self.is_queued() {
+            JobStatus::Queued
+        } else {
+            JobStatus::Running
+        }
+    }
+
+    fn record(&self, token: u64) -> JobRecord {
+        let mut record = JobRecord::new(token, self.name.clone(), self.digest.clone(), self.create_time);
+        record.status = self.status();
+        record.result = self.result.clone();
+        record.error = self.error.clone();
+        record.start_time = self.start_time;
+        record.finish_time = self.finish_time;
+        record.owner = self.owner.clone();
+        record.idempotency_key = self.idempotency_key.clone();
+        record.sector_id = self.sector_id;
+        record.prover_id = self.prover_id.clone();
+        record.failed_attempts = self.failed_attempts.clone();
+        record.dependency = self.dependency.clone();
+        record.options = self.options.clone();
+        record.callback = self.callback.clone();
+
+        record
+    }
+
+    fn info(&self, token: u64) -> JobInfo {
+        JobInfo {
+            token,
+            kind: self.name.clone(),
+            status: self.status(),
+            create_time: self.create_time,
+            last_query: Some(self.last_query),
+            duration_secs: self.run_secs(),
+            owner: self.owner.clone(),
+            sector_id: self.sector_id,
+            prover_id: self.prover_id.clone(),
+            failed_attempts: self.failed_attempts.clone(),
+            depends_on: self.dependency.as_ref().map(|x| x.token),
+        }
+    }
+
+    fn last_query_since_secs(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(self.last_query)
+            .map(|x| x.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// sha256 of job inputs, recorded in job store
+pub fn input_digest<T: AsRef<[u8]>>(data: T) -> String {
+    Sha256::digest(data.as_ref())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// identify who submitted a request without keeping the token itself. the owner stays
+/// the same when a client rotates its jwt tokens, clients without a token are identified
+/// by their certificate subject
+fn request_owner(req: &HttpRequest) -> Option<String> {
+    let identity = match req.headers().get("Authorization") {
+        Some(header) => {
+            let state = req.app_data::<Data<ServState>>();
+            let token = header.to_str().ok();
+            match state.zip(token).and_then(|(state, token)| state.authenticate(token)) {
+                Some((name, _)) => name,
+                // only unknown tokens get here, when auth is disabled
+                None => format!("header:{}", String::from_utf8_lossy(header.as_bytes())),
+            }
+        }
+        None => format!("cert:{}", req.extensions().get::<ClientSubject>()?.0),
+    };
+
+    Some(input_digest(identity)[..8].to_string())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListJobsParam {
+    pub kind: Option<String>,
+    pub status: Option<JobStatus>,
+    /// only jobs created within this many seconds
+    pub max_age_secs: Option<u64>,
+    /// only jobs created at least this many seconds ago
+    pub min_age_secs: Option<u64>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ListJobsResponse {
+    pub total: usize,
+    pub jobs: Vec<JobInfo>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DrainStatus {
+    pub draining: bool,
+    pub running: u64,
+    pub queued: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchResponse {
+    pub group: u64,
+    /// state of each submitted item, in request order
+    pub items: Vec<PollingState>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GroupJob {
+    pub token: u64,
+    pub state: PollingState,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct GroupStatus {
+    pub group: u64,
+    pub total: u64,
+    /// queued or running
+    pub pending: u64,
+    pub done: u64,
+    pub failed: u64,
+    /// cancelled, interrupted or removed
+    pub dropped: u64,
+    pub jobs: Vec<GroupJob>,
+}
+
+/// default page size of job listing
+const LIST_JOBS_LIMIT: usize = 100;
+
+type SharedProp = Arc<Mutex<WorkerProp>>;
+/// order in which queued jobs start, see `WorkerProp::schedule_key`
+type ScheduleKey = (u8, bool, u64, u64);
+/// a started job whose worker is not spawned yet
+type Launch = (u64, SharedProp, Arc<Value>);
+
+/// Job registry shared by all http workers.
+///
+/// Each job has its own lock so polling jobs never waits on each other. Locks are
+/// always taken in order `schedule_lock` -> `workers` -> a single job -> `history`/`records`,
+/// and `workers` is never held while waiting for a job lock. Worker processes are spawned
+/// after all locks are released, since writing job inputs may take a while.
+#[derive(Debug)]
+pub struct ServState {
+    workers: RwLock<HashMap<u64, SharedProp>>,
+    // jobs restored from store which have no running worker
+    records: RwLock<HashMap<u64, JobRecord>>,
+    store: Option<Arc<JobStore>>,
+    // durations of finished jobs, for progress estimation
+    history: RwLock<HashMap<String, Vec<u64>>>,
+    // job tokens of batch submissions
+    groups: RwLock<HashMap<u64, Vec<u64>>>,
+    // admission and scheduling decisions are made one at a time
+    schedule_lock: Mutex<()>,
+    // sorted schedule keys of queued jobs of each kind, taken by last scheduling
+    queue: RwLock<HashMap<String, Vec<ScheduleKey>>>,
+    // no new job is accepted or started once set
+    draining: AtomicBool,
+    // jobs whose worker should be spawned, see `start_launcher`
+    launcher: Mutex<Sender<Launch>>,
+    // where uploaded files are saved
+    upload_dir: PathBuf,
+    sandbox: Sandbox,
+    config: Config,
+}
+
+impl ServState {
+    pub fn new(config: Config) -> Self {
+        // NOTE: ensure ServState is init only once
+        assert_eq!(WORKER_INIT.swap(true, Ordering::SeqCst), false);
+
+        let store = config
+            .state_dir
+            .as_ref()
+            .map(|dir| Arc::new(JobStore::open(dir).expect("open job store failed")));
+
+        let mut records = HashMap::new();
+        let mut history = HashMap::new();
+        let mut groups = HashMap::new();
+        if let Some(store) = &store {
+            history = store.load_history().unwrap_or_else(|e| {
+                warn!("load job history failed: {:?}", e);
+                HashMap::new()
+            });
+            groups = store.load_groups().unwrap_or_else(|e| {
+                warn!("load job groups failed: {:?}", e);
+                HashMap::new()
+            });
+
+            for mut record in store.load().expect("load job store failed") {
+                // jobs running or queued when server stopped will never finish
+                if record.status == JobStatus::Running || record.status == JobStatus::Queued {
+                    warn!("Job {} {} interrupted", record.token, record.kind);
+                    record.status = JobStatus::Interrupted;
+                    // so the record expires like any other finished job
+                    record.finish_time = Some(SystemTime::now());
+                    if record.options.callback_url.is_some() {
+                        record.callback = Some(CallbackStatus::Pending);
+                    }
+                }
+
+                // callback delivery was broken by restart, send it again
+                if record.callback == Some(CallbackStatus::Delivering) {
+                    record.callback = Some(CallbackStatus::Pending);
+                }
+
+                if let Err(e) = store.save(&record) {
+                    error!("save job record {} failed: {:?}", record.token, e);
+                }
+
+                records.insert(record.token, record);
+            }
+
+            // never reuse a token handed out before restart, even if its job is already gone
+            let saved_token = store.load_next_token().unwrap_or_else(|e| {
+                warn!("load next token failed: {:?}", e);
+                0
+            });
+            let next_token = records
+                .keys()
+                .chain(groups.keys())
+                .map(|x| x + 1)
+                .chain(Some(saved_token))
+                .max()
+                .unwrap_or(0);
+            WORKER_TOKEN.store(next_token, Ordering::SeqCst);
+            info!("restored {} jobs from store, next token {}", records.len(), next_token);
+        }
+
+        let work_dir = open_work_dir(config.state_dir.as_deref()).expect("open work dir failed");
+        debug!("worker files are kept in {:?}", work_dir);
+        let launcher = Mutex::new(Self::start_launcher(work_dir.clone(), store.clone()));
+        let upload_dir = std::fs::canonicalize(&config.upload_dir).expect("invalid upload dir");
+        // uploads are kept inside storage roots so they can be passed to jobs
+        let mut roots = config.storage_roots.clone();
+        if !roots.is_empty() {
+            roots.push(upload_dir.to_string_lossy().into_owned());
+        }
+        let sandbox = Sandbox::new(&roots).unwrap_or_else(|e| panic!("{}", e));
+
+        Self {
+            workers: RwLock::new(HashMap::new()),
+            records: RwLock::new(records),
+            store,
+            history: RwLock::new(history),
+            groups: RwLock::new(groups),
+            schedule_lock: Mutex::new(()),
+            queue: RwLock::new(HashMap::new()),
+            draining: AtomicBool::new(false),
+            launcher,
+            upload_dir,
+            sandbox,
+            config,
+        }
+    }
+
+    pub fn sandbox(&self) -> &Sandbox {
+        &self.sandbox
+    }
+
+    pub fn upload_dir(&self) -> &Path {
+        &self.upload_dir
+    }
+
+    /// how long running jobs may take to finish once draining
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.drain_timeout_secs)
+    }
+
+    fn worker(&self, token: u64) -> Option<SharedProp> {
+        self.workers.read().unwrap().get(&token).cloned()
+    }
+
+    /// snapshot of current jobs, so job locks are taken without holding the registry
+    fn worker_list(&self) -> Vec<(u64, SharedProp)> {
+        self.workers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(token, prop)| (*token, prop.clone()))
+            .collect()
+    }
+
+    fn save_record(&self, token: u64, prop: &WorkerProp) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save(&prop.record(token)) {
+                error!("save job record {} failed: {:?}", token, e);
+            }
+        }
+    }
+
+    fn save_restored_record(&self, record: &JobRecord) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save(record) {
+                error!("save job record {} failed: {:?}", record.token, e);
+            }
+        }
+    }
+
+    fn remove_record(&self, token: u64) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.remove(token) {
+                error!("remove job record {} failed: {:?}", token, e);
+            }
+        }
+    }
+
+    pub fn debug_info(&self) -> String {
+        let mut info = format!("{:#?}\n", self);
+        info += &format!("reserved: {:#?}\n", self.reserved());
+
+        for (token, prop) in self.worker_list() {
+            let prop = prop.lock().unwrap();
+            if prop.is_running() {
+                info += &format!(
+                    "progress {}: {:#?}\n",
+                    token,
+                    prop.progress(&self.history.read().unwrap())
+                );
+            }
+        }
+
+        info
+    }
+
+    /// record duration of a successfully finished job
+    fn add_history(&self, prop: &WorkerProp) {
+        let secs = match prop.run_secs() {
+            // a failed run says nothing about how long a successful one takes
+            Some(secs) if prop.status() == JobStatus::Done => secs,
+            _ => return,
+        };
+
+        let mut history = self.history.write().unwrap();
+        let durations = history.entry(prop.history_key()).or_default();
+        durations.push(secs);
+        if durations.len() > HISTORY_LEN {
+            durations.remove(0);
+        }
+
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save_history(&history) {
+                error!("save job history failed: {:?}", e);
+            }
+        }
+    }
+
+    /// stable name of token holder and scopes granted to token, `None` if token is unknown.
+    /// jwt tokens are named by their subject, so rotated tokens of a client keep the name
+    fn authenticate(&self, token: &str) -> Option<(String, Vec<Scope>)> {
+        let token = token.strip_prefix("Bearer ").unwrap_or(token);
+        if self.config.allow_tokens.iter().any(|x| x == token) {
+            return Some((format!("token:{}", token), vec![Scope::Admin]));
+        }
+
+        if let Some(entry) = self.config.tokens.iter().find(|x| x.token == token) {
+            return Some((format!("token:{}", token), entry.scopes.clone()));
+        }
+
+        let secret = self.config.jwt_secret.as_ref()?;
+        let claims = auth::verify(secret, token)?;
+        let name = match claims.sub {
+            Some(sub) => format!("jwt:{}", sub),
+            // e.g. lotus tokens, all of them belong to one client
+            None => "jwt".to_string(),
+        };
+
+        Some((name, claims.scopes))
+    }
+
+    /// scopes granted to token, `None` if token is unknown
+    pub fn token_scopes<S: AsRef<str>>(&self, token: S) -> Option<Vec<Scope>> {
+        self.authenticate(token.as_ref()).map(|x| x.1)
+    }
+
+    /// scopes granted to client certificate with subject common name `subject`
+    pub fn client_scopes(&self, subject: &str) -> Option<Vec<Scope>> {
+        self.config
+            .client_certs
+            .iter()
+            .find(|x| x.subject == subject)
+            .map(|x| x.scopes.clone())
+    }
+
+    /// number of jobs of given kind matching `filter`
+    fn count_jobs<F: Fn(&WorkerProp) -> bool>(&self, name: &str, filter: F) -> u64 {
+        self.worker_list()
+            .iter()
+            .filter(|(_, prop)| {
+                let prop = prop.lock().unwrap();
+                prop.name == name && filter(&prop)
+            })
+            .count() as u64
+    }
+
+    pub fn job_num<S: AsRef<str>>(&self, name: S) -> u64 {
+        self.count_jobs(name.as_ref(), |prop| prop.is_running())
+    }
+
+    pub fn queued_num<S: AsRef<str>>(&self, name: S) -> u64 {
+        self.count_jobs(name.as_ref(), |prop| prop.is_queued())
+    }
+
+    pub fn job_limit<S: AsRef<str>>(&self, name: S) -> u64 {
+        *self.config.job_limits.get(name.as_ref()).unwrap_or(&u64::max_value())
+    }
+
+    pub fn job_available<S: AsRef<str>>(&self, name: S) -> bool {
+        let num = self.job_num(name.as_ref());
+        let limit = self.job_limit(name.as_ref());
+
+        num < limit
+    }
+
+    /// footprint of job from `job_resources`, entry of exact sector size is preferred
+    fn job_footprint(&self, prop: &WorkerProp) -> Resources {
+        let entries = self.config.job_resources.iter().filter(|x| x.kind == prop.name);
+        let mut footprint = None;
+
+        for entry in entries {
+            match entry.sector_size {
+                Some(size) if Some(size) == prop.sector_size => return entry.resources,
+                None => footprint = Some(entry.resources),
+                _ => {}
+            }
+        }
+
+        footprint.unwrap_or_default()
+    }
+
+    /// resources taken by running jobs
+    fn reserved(&self) -> Resources {
+        self.worker_list()
+            .iter()
+            .map(|(_, prop)| prop.lock().unwrap())
+            .filter(|prop| prop.is_running())
+            .fold(Resources::default(), |sum, prop| sum + prop.footprint)
+    }
+
+    fn resources_available(&self, footprint: &Resources) -> bool {
+        match &self.config.resources {
+            Some(budget) => (self.reserved() + *footprint).fits_in(budget),
+            None => true,
+        }
+    }
+
+    /// whether a new job can be accepted, either to run or to wait in queue
+    pub fn queue_available<S: AsRef<str>>(&self, name: S) -> bool {
+        if self.job_available(name.as_ref()) {
+            return true;
+        }
+
+        match self.config.job_queue_limits.get(name.as_ref()) {
+            Some(limit) => self.queued_num(name.as_ref()) < *limit,
+            None => true,
+        }
+    }
+
+    /// 1-based position of a queued job among queued jobs of the same kind, taken from
+    /// the snapshot of last scheduling so polling doesn't lock other jobs
+    fn queue_position(&self, name: &str, key: ScheduleKey) -> u64 {
+        let ahead = match self.queue.read().unwrap().get(name) {
+            Some(keys) => keys.binary_search(&key).unwrap_or_else(|x| x),
+            None => 0,
+        };
+
+        ahead as u64 + 1
+    }
+
+    /// start queued jobs in schedule order while there are free slots and resources,
+    /// a job waiting for resources blocks all jobs after it so it won't be starved.
+    /// caller must hold `schedule_lock`, and pass returned jobs to `launch` once it's released
+    fn schedule(&self) -> Vec<Launch> {
+        self.resolve_dependencies();
+
+        let mut waiting: HashMap<String, Vec<ScheduleKey>> = HashMap::new();
+        let mut ready: Vec<(ScheduleKey, SharedProp)> = vec![];
+        for (token, prop) in self.worker_list() {
+            let key = {
+                let prop = prop.lock().unwrap();
+                if !prop.is_queued() {
+                    continue;
+                }
+
+                let key = prop.schedule_key(token);
+                waiting.entry(prop.name.clone()).or_default().push(key);
+                if prop.retry_pending() || prop.dependency.is_some() {
+                    continue;
+                }
+                key
+            };
+            ready.push((key, prop));
+        }
+        ready.sort_unstable_by_key(|(key, _)| *key);
+
+        let mut launches = vec![];
+        for (key, prop) in ready {
+            if self.is_draining() {
+                break;
+            }
+
+            let token = key.3;
+            let (name, footprint) = {
+                let prop = prop.lock().unwrap();
+                (prop.name.clone(), prop.footprint)
+            };
+
+            if !self.job_available(&name) {
+                continue;
+            }
+
+            if !self.resources_available(&footprint) {
+                debug!("Job {} waiting for resources {:?}", token, footprint);
+                break;
+            }
+
+            let input = {
+                let mut prop = prop.lock().unwrap();
+                let input = prop.start(token);
+                self.save_record(token, &prop);
+                input
+            };
+            if let Some(input) = input {
+                if let Some(keys) = waiting.get_mut(&name) {
+                    keys.retain(|x| *x != key);
+                }
+                launches.push((token, prop, input));
+            }
+        }
+
+        for keys in waiting.values_mut() {
+            keys.sort_unstable();
+        }
+        *self.queue.write().unwrap() = waiting;
+
+        launches
+    }
+
+    /// thread spawning workers, so input files are written without holding any lock.
+    /// it lives as long as the server, since workers die with the thread which spawned them
+    fn start_launcher(work_dir: PathBuf, store: Option<Arc<JobStore>>) -> Sender<Launch> {
+        let (tx, rx) = channel::<Launch>();
+
+        std::thread::spawn(move || {
+            for (token, prop, input) in rx {
+                let kind = prop.lock().unwrap().name.clone();
+                let r = JobProcess::spawn(&kind, token, &input, &work_dir);
+
+                let mut prop = prop.lock().unwrap();
+                prop.launched(token, r);
+                if let Some(store) = &store {
+                    if let Err(e) = store.save(&prop.record(token)) {
+                        error!("save job record {} failed: {:?}", token, e);
+                    }
+                }
+            }
+        });
+
+        tx
+    }
+
+    /// spawn workers of jobs started by `schedule`
+    fn launch(&self, launches: Vec<Launch>) {
+        let launcher = self.launcher.lock().unwrap();
+        for launch in launches {
+            launcher.send(launch).expect("worker launcher exited");
+        }
+    }
+
+    /// feed outputs of finished dependencies into waiting jobs, jobs whose dependency
+    /// did not succeed are failed
+    fn resolve_dependencies(&self) {
+        for (token, prop) in self.worker_list() {
+            let (dependency, owner) = {
+                let prop = prop.lock().unwrap();
+                match &prop.dependency {
+                    Some(dependency) if prop.is_queued() => (dependency.token, prop.owner.clone()),
+                    _ => continue,
+                }
+            };
+
+            // a job can only depend on jobs submitted before it, so a chain never loops
+            let output = if dependency >= token {
+                Some(Err("job is not submitted before".to_string()))
+            } else {
+                self.dependency_output(dependency, &owner)
+            };
+
+            let output = match output {
+                Some(output) => output,
+                None => continue,
+            };
+
+            let mut prop = prop.lock().unwrap();
+            if !prop.is_queued() {
+                continue;
+            }
+
+            match output {
+                Ok(output) => {
+                    debug!("Job {} got output of job {}", token, dependency);
+                    prop.resolve_dependency(output);
+                    prop.footprint = self.job_footprint(&prop);
+                }
+                Err(reason) => {
+                    warn!("Job {} dependency {} failed: {}", token, dependency, reason);
+                    prop.fail(
+                        token,
+                        PollingError::DependencyFailed {
+                            token: dependency,
+                            reason,
+                        },
+                    );
+                }
+            }
+            self.save_record(token, &prop);
+        }
+    }
+
+    /// `Ok` value of a finished job, `None` while it's not finished yet
+    fn dependency_output(&self, token: u64, owner: &Option<String>) -> Option<Result<Value, String>> {
+        let dependency_owner = match self.worker(token) {
+            Some(prop) => Some(prop.lock().unwrap().owner.clone()),
+            None => self.records.read().unwrap().get(&token).map(|x| x.owner.clone()),
+        };
+        if dependency_owner.map(|x| &x != owner).unwrap_or(false) {
+            return Some(Err("job is submitted by another client".to_string()));
+        }
+
+        match self.get(token) {
+            PollingState::Done(r) => match r.get("Ok") {
+                Some(output) => Some(Ok(output.clone())),
+                None => Some(Err(format!("job finished with {}", r))),
+            },
+            state if state.is_finished() => Some(Err(format!("job finished with {:?}", state))),
+            _ => None,
+        }
+    }
+
+    /// whether output of job is still needed by a waiting job
+    fn has_dependents(&self, token: u64) -> bool {
+        self.worker_list().iter().any(|(_, prop)| {
+            let prop = prop.lock().unwrap();
+            prop.is_queued() && prop.dependency.as_ref().map(|x| x.token == token).unwrap_or(false)
+        })
+    }
+
+    /// find a queued, running or finished job which the new job duplicates,
+    /// it's an error if idempotency key of the new job was used for a different request
+    fn find_duplicate(&self, prop: &WorkerProp) -> Result<Option<u64>, PollingError> {
+        let reusable = |status: JobStatus| {
+            status == JobStatus::Queued || status == JobStatus::Running || status == JobStatus::Done
+        };
+        let check =
+            |token: u64, status: JobStatus, kind: &str, digest: &str, owner: &Option<String>, key: &Option<String>| {
+                if prop.key_reused(kind, digest, owner, key) {
+                    Some(Err(PollingError::IdempotencyKeyReused { token }))
+                } else if reusable(status) && prop.same_job(kind, digest, owner, key) {
+                    Some(Ok(token))
+                } else {
+                    None
+                }
+            };
+
+        let worker = self.worker_list().into_iter().find_map(|(token, x)| {
+            let x = x.lock().unwrap();
+            check(token, x.status(), &x.name, &x.digest, &x.owner, &x.idempotency_key)
+        });
+
+        worker
+            .or_else(|| {
+                self.records
+                    .read()
+                    .unwrap()
+                    .values()
+                    .find_map(|x| check(x.token, x.status, &x.kind, &x.digest, &x.owner, &x.idempotency_key))
+            })
+            .transpose()
+    }
+
+    /// stop accepting and starting jobs, return false if already draining
+    pub fn start_drain(&self) -> bool {
+        !self.draining.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn drain_status(&self) -> DrainStatus {
+        let mut status = DrainStatus {
+            draining: self.is_draining(),
+            running: 0,
+            queued: 0,
+        };
+
+        for (_, prop) in self.worker_list() {
+            let prop = prop.lock().unwrap();
+            if prop.is_running() {
+                status.running += 1;
+            } else if prop.is_queued() {
+                status.queued += 1;
+            }
+        }
+
+        status
+    }
+
+    /// kill jobs still running at shutdown, they are recorded as interrupted
+    pub fn interrupt_running(&self) {
+        for (token, prop) in self.worker_list() {
+            let mut prop = prop.lock().unwrap();
+            self.poll_job(token, &mut prop);
+
+            if prop.is_running() {
+                warn!("Job {} {} interrupted", token, prop.name);
+                prop.interrupt(token);
+                self.save_record(token, &prop);
+            }
+        }
+    }
+
+    /// add a new job, `None` if the queue of its kind is full
+    pub fn enqueue(&self, mut prop: WorkerProp) -> Option<PollingState> {
+        let guard = self.schedule_lock.lock().unwrap();
+
+        // resubmitted job gets token of the existing one
+        match self.find_duplicate(&prop) {
+            Ok(Some(token)) => {
+                debug!("Job {} {} resubmitted", token, prop.name);
+                return Some(PollingState::Started(token));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Job {} rejected: {:?}", prop.name, e);
+                return Some(PollingState::Error(e));
+            }
+        }
+
+        if !self.queue_available(&prop.name) {
+            return None;
+        }
+
+        // fail fast, no token is handed out for a job which can never be in time
+        if let Some(e) = prop.check_deadline(&self.history.read().unwrap()) {
+            warn!("Job {} rejected: {:?}", prop.name, e);
+            return Some(PollingState::Error(e));
+        }
+
+        prop.footprint = self.job_footprint(&prop);
+        prop.retry = self.config.job_retries.get(&prop.name).cloned();
+        if let Some(budget) = &self.config.resources {
+            if !prop.footprint.fits_in(budget) {
+                let reason = format!("job needs {:?} but total is {:?}", prop.footprint, budget);
+                warn!("Job {} rejected: {}", prop.name, reason);
+                return Some(PollingState::Error(PollingError::Unschedulable { reason }));
+            }
+        }
+
+        let token = self.next_token();
+        let prop = Arc::new(Mutex::new(prop));
+        self.workers.write().unwrap().insert(token, prop.clone());
+
+        // jobs submitted earlier or with higher priority go first
+        let launches = self.schedule();
+        {
+            let prop = prop.lock().unwrap();
+            if prop.is_queued() {
+                debug!("Job {} {} queued", token, prop.name);
+                self.save_record(token, &prop);
+            }
+        }
+
+        drop(guard);
+        self.launch(launches);
+
+        Some(PollingState::Started(token))
+    }
+
+    /// enqueue items of a batch one by one, accepted jobs are tracked by a group token
+    pub fn enqueue_batch(&self, props: Vec<WorkerProp>) -> BatchResponse {
+        let group = self.next_token();
+        let items: Vec<PollingState> = props
+            .into_iter()
+            .map(|prop| {
+                self.enqueue(prop)
+                    .unwrap_or(PollingState::Error(PollingError::QueueFull))
+            })
+            .collect();
+
+        let tokens = items
+            .iter()
+            .filter_map(|x| match x {
+                PollingState::Started(token) => Some(*token),
+                _ => None,
+            })
+            .collect();
+        debug!("Group {} submitted: {:?}", group, tokens);
+
+        let mut groups = self.groups.write().unwrap();
+        groups.insert(group, tokens);
+        self.save_groups(&groups);
+
+        BatchResponse { group, items }
+    }
+
+    /// hand out a new job or group token, the counter is persisted so tokens stay unique
+    /// across restarts
+    fn next_token(&self) -> u64 {
+        let token = WORKER_TOKEN.fetch_add(1, Ordering::SeqCst);
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save_next_token(token + 1) {
+                error!("save next token failed: {:?}", e);
+            }
+        }
+
+        token
+    }
+
+    fn save_groups(&self, groups: &HashMap<u64, Vec<u64>>) {
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save_groups(groups) {
+                error!("save job groups failed: {:?}", e);
+            }
+        }
+    }
+
+    /// summary of jobs in a batch, `None` if group is unknown
+    pub fn get_group(&self, group: u64) -> Option<GroupStatus> {
+        let tokens = self.groups.read().unwrap().get(&group)?.clone();
+        let mut status = GroupStatus {
+            group,
+            ..Default::default()
+        };
+
+        for token in tokens {
+            let state = self.get(token);
+            status.total += 1;
+            match &state {
+                PollingState::Done(_) => status.done += 1,
+                PollingState::Error(PollingError::NotExist) => status.dropped += 1,
+                PollingState::Error(_) => status.failed += 1,
+                x if !x.is_finished() => status.pending += 1,
+                _ => status.dropped += 1,
+            }
+
+            status.jobs.push(GroupJob { token, state });
+        }
+
+        Some(status)
+    }
+
+    /// check if a running job is just finished, and persist its result
+    fn poll_job(&self, token: u64, prop: &mut WorkerProp) {
+        if prop.is_running() && prop.try_finish() {
+            debug!("Job {} finished", token);
+            self.add_history(prop);
+            self.save_record(token, prop);
+        }
+    }
+
+    /// collect results of finished workers and persist them
+    pub fn update(&self) {
+        for (token, prop) in self.worker_list() {
+            self.poll_job(token, &mut prop.lock().unwrap());
+        }
+
+        let launches = {
+            let _guard = self.schedule_lock.lock().unwrap();
+            self.check_time_limits();
+            self.schedule()
+        };
+        self.launch(launches);
+
+        self.remove_expired();
+    }
+
+    /// fail unfinished jobs which timed out or can not meet their deadlines
+    fn check_time_limits(&self) {
+        for (token, prop) in self.worker_list() {
+            let mut prop = prop.lock().unwrap();
+            if prop.is_finished() {
+                continue;
+            }
+
+            let missed = prop
+                .check_timeout(&self.config.job_timeouts)
+                .or_else(|| prop.check_deadline(&self.history.read().unwrap()));
+            if let Some(e) = missed {
+                warn!("Job {} failed: {:?}", token, e);
+                prop.fail(token, e);
+                self.save_record(token, &prop);
+            }
+        }
+    }
+
+    fn result_expired(&self, finish_time: Option<SystemTime>) -> bool {
+        finish_time
+            .and_then(|x| SystemTime::now().duration_since(x).ok())
+            .map(|x| x.as_secs() >= self.config.result_ttl_secs)
+            .unwrap_or(false)
+    }
+
+    /// drop finished results which are not acknowledged in time
+    fn remove_expired(&self) {
+        let mut expired: Vec<u64> = self
+            .worker_list()
+            .iter()
+            .filter(|(_, prop)| self.result_expired(prop.lock().unwrap().finish_time))
+            .map(|(token, _)| *token)
+            .collect();
+        expired.extend(
+            self.records
+                .read()
+                .unwrap()
+                .values()
+                .filter(|x| self.result_expired(x.finish_time))
+                .map(|x| x.token),
+        );
+
+        for token in expired {
+            debug!("Job {} removed dut to result expired", token);
+            self.workers.write().unwrap().remove(&token);
+            self.records.write().unwrap().remove(&token);
+            self.remove_record(token);
+        }
+
+        // forget groups whose jobs are all gone
+        let exists = |token: &u64| {
+            self.workers.read().unwrap().contains_key(token) || self.records.read().unwrap().contains_key(token)
+        };
+        let mut groups = self.groups.write().unwrap();
+        let len = groups.len();
+        groups.retain(|_, tokens| tokens.iter().any(exists));
+        if groups.len() != len {
+            self.save_groups(&groups);
+        }
+    }
+
+    pub fn get(&self, token: u64) -> PollingState {
+        let prop = match self.worker(token) {
+            Some(prop) => prop,
+            None => return self.get_record(token),
+        };
+
+        let (name, key) = {
+            let mut prop = prop.lock().unwrap();
+            self.poll_job(token, &mut prop);
+
+            // update query time
+            prop.last_query = SystemTime::now();
+
+            if !prop.is_queued() {
+                return prop.state(&self.history.read().unwrap());
+            }
+
+            (prop.name.clone(), prop.schedule_key(token))
+        };
+
+        PollingState::Queued {
+            position: self.queue_position(&name, key),
+        }
+    }
+
+    fn get_record(&self, token: u64) -> PollingState {
+        self.records
+            .read()
+            .unwrap()
+            .get(&token)
+            .map(|x| x.state())
+            .unwrap_or(PollingState::Error(PollingError::NotExist))
+    }
+
+    /// collect callbacks of finished jobs which are not delivered yet
+    pub fn take_callbacks(&self) -> Vec<Callback> {
+        let max_attempts = self.config.callback_max_attempts;
+        let mut callbacks = vec![];
+
+        for (token, prop) in self.worker_list() {
+            let mut prop = prop.lock().unwrap();
+            let url = match &prop.options.callback_url {
+                Some(url) if prop.is_finished() => url.clone(),
+                _ => continue,
+            };
+
+            if prop.callback.is_none() || prop.callback == Some(CallbackStatus::Pending) {
+                prop.callback = Some(CallbackStatus::Delivering);
+                self.save_record(token, &prop);
+                callbacks.push(Callback {
+                    token,
+                    url,
+                    secret: prop.options.callback_secret.clone(),
+                    state: prop.state(&self.history.read().unwrap()),
+                    max_attempts,
+                });
+            }
+        }
+
+        for record in self.records.write().unwrap().values_mut() {
+            if let (Some(CallbackStatus::Pending), Some(url)) = (&record.callback, &record.options.callback_url) {
+                callbacks.push(Callback {
+                    token: record.token,
+                    url: url.clone(),
+                    secret: record.options.callback_secret.clone(),
+                    state: record.state(),
+                    max_attempts,
+                });
+                record.callback = Some(CallbackStatus::Delivering);
+                self.save_restored_record(record);
+            }
+        }
+
+        callbacks
+    }
+
+    pub fn set_callback_status(&self, token: u64, status: CallbackStatus) {
+        if let Some(prop) = self.worker(token) {
+            let mut prop = prop.lock().unwrap();
+            prop.callback = Some(status);
+            self.save_record(token, &prop);
+        } else if let Some(record) = self.records.write().unwrap().get_mut(&token) {
+            record.callback = Some(status);
+            self.save_restored_record(record);
+        }
+    }
+
+    pub fn list_jobs(&self, param: &ListJobsParam) -> ListJobsResponse {
+        let age_secs = |x: &JobInfo| {
+            SystemTime::now()
+                .duration_since(x.create_time)
+                .map(|x| x.as_secs())
+                .unwrap_or(0)
+        };
+
+        let mut jobs: Vec<JobInfo> = self
+            .worker_list()
+            .iter()
+            .map(|(token, prop)| prop.lock().unwrap().info(*token))
+            .chain(self.records.read().unwrap().values().map(|x| x.info()))
+            .filter(|x| param.kind.as_ref().map(|k| &x.kind == k).unwrap_or(true))
+            .filter(|x| param.status.map(|s| x.status == s).unwrap_or(true))
+            .filter(|x| param.max_age_secs.map(|a| age_secs(x) <= a).unwrap_or(true))
+            .filter(|x| param.min_age_secs.map(|a| age_secs(x) >= a).unwrap_or(true))
+            .collect();
+        jobs.sort_unstable_by_key(|x| x.token);
+
+        let total = jobs.len();
+        let jobs = jobs
+            .into_iter()
+            .skip(param.offset)
+            .take(param.limit.unwrap_or(LIST_JOBS_LIMIT))
+            .collect();
+
+        ListJobsResponse { total, jobs }
+    }
+
+    /// release a finished job, running jobs are left untouched
+    pub fn ack(&self, token: u64) -> PollingState {
+        let finished = match self.worker(token) {
+            Some(prop) => prop.lock().unwrap().is_finished(),
+            None => self.records.read().unwrap().contains_key(&token),
+        };
+
+        // keep output until jobs depending on it have taken it
+        if !finished || self.has_dependents(token) {
+            return self.get(token);
+        }
+
+        debug!("Job {} removed dut to acknowledged", token);
+        self.workers.write().unwrap().remove(&token);
+        self.records.write().unwrap().remove(&token);
+        self.remove_record(token);
+
+        PollingState::Removed
+    }
+
+    /// cancel a queued or running job, finished jobs are removed
+    pub fn remove(&self, token: u64) -> PollingState {
+        if let Some(prop) = self.worker(token) {
+            {
+                let mut prop = prop.lock().unwrap();
+                self.poll_job(token, &mut prop);
+
+                if !prop.is_finished() {
+                    debug!("Job {} cancelled", token);
+                    prop.cancel(token);
+                    self.save_record(token, &prop);
+
+                    return PollingState::Cancelled;
+                }
+            }
+
+            debug!("Job {} force removed", token);
+            self.workers.write().unwrap().remove(&token);
+            self.remove_record(token);
+
+            return PollingState::Removed;
+        }
+
+        if self.records.write().unwrap().remove(&token).is_some() {
+            debug!("Job {} record removed", token);
+            self.remove_record(token);
+
+            return PollingState::Removed;
+        }
+
+        PollingState::Error(PollingError::NotExist)
+    }
+}
+
+/// new jobs are rejected while draining
+pub fn draining_response() -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .header("Retry-After", DRAIN_RETRY_AFTER_SECS.to_string())
+        .finish()
+}
+
+/// enqueue a job of request, rejected with 429 when its queue is full
+/// or with 503 when server is draining, reusing an idempotency key for
+/// a different request is rejected with 422
+pub fn submit_job(state: &ServState, req: &HttpRequest, kind: &str, input: Value, options: JobOptions) -> HttpResponse {
+    let prop = WorkerProp::new(kind.to_string(), input)
+        .with_options(options)
+        .with_request(req);
+
+    submit_prop(state, prop)
+}
+
+/// enqueue a prepared job, see `submit_job`
+pub fn submit_prop(state: &ServState, prop: WorkerProp) -> HttpResponse {
+    if state.is_draining() {
+        return draining_response();
+    }
+
+    match state.enqueue(prop) {
+        Some(response @ PollingState::Error(PollingError::IdempotencyKeyReused { .. })) => {
+            HttpResponse::UnprocessableEntity().json(response)
+        }
+        Some(response) => HttpResponse::Ok().json(response),
+        None => HttpResponse::TooManyRequests().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let yaml = format!(
+            "listen_addr: 127.0.0.1:0\njob_limits: {{C2: 0}}\nupload_dir: {:?}\n",
+            std::env::temp_dir()
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn retry_crashes() {
+        let mut prop = WorkerProp::new("C2".to_string(), json!({}));
+        prop.retry = Some(serde_yaml::from_str("max_attempts: 3").unwrap());
+        let crashed = |signal: Option<i32>, stderr_tail: &str| {
+            Err(PollingError::Crashed {
+                signal,
+                stderr_tail: stderr_tail.to_string(),
+            })
+        };
+
+        // killed by oom killer
+        assert_eq!(prop.retry_delay(&crashed(Some(9), "")), None);
+        assert!(prop.retry_delay(&crashed(Some(7), "")).is_some());
+        assert!(prop
+            .retry_delay(&crashed(None, "No such file or directory (os error 2)"))
+            .is_some());
+        assert_eq!(prop.retry_delay(&crashed(None, "assertion failed")), None);
+
+        let invalid = format!("{}: missing field", INVALID_INPUT);
+        assert_eq!(prop.retry_delay(&Ok(json!({ "Err": invalid }))), None);
+        assert!(prop.retry_delay(&Ok(json!({"Err": "Os { code: 5 }"}))).is_some());
+    }
+
+    // a server state is created only once per process, jobs never start since C2 limit is 0
+    #[test]
+    fn resubmit_after_failure() {
+        let state = ServState::new(config());
+        let submit = || match state.enqueue(WorkerProp::new("C2".to_string(), json!({"sector_id": 1}))) {
+            Some(PollingState::Started(token)) => token,
+            x => panic!("job not accepted: {:?}", x),
+        };
+        let finish = |token: u64, r: Value| {
+            let prop = state.worker(token).unwrap();
+            let mut prop = prop.lock().unwrap();
+            prop.finish(Ok(r));
+            prop.status()
+        };
+
+        let failed = submit();
+        assert_eq!(submit(), failed);
+        assert_eq!(finish(failed, json!({"Err": "proof failed"})), JobStatus::Failed);
+
+        // a failed proof is run again
+        let retried = submit();
+        assert_ne!(retried, failed);
+        assert_eq!(finish(retried, json!({"Ok": [1, 2, 3]})), JobStatus::Done);
+
+        // a succeeded proof is reused
+        assert_eq!(submit(), retried);
+    }
+}
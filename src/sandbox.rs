@@ -0,0 +1,324 @@
+use crate::post_data::*;
+use crate::seal_data::*;
+use crate::types::WebPrivateReplicas;
+use actix_web::{HttpResponse, ResponseError};
+use log::*;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// a file path parameter rejected by the sandbox
+#[derive(Debug, Serialize)]
+pub struct PathError {
+    pub field: String,
+    pub path: String,
+    pub reason: String,
+}
+
+impl PathError {
+    pub fn new<F: Into<String>, P: Into<String>, R: Into<String>>(field: F, path: P, reason: R) -> Self {
+        Self {
+            field: field.into(),
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid path in `{}`: {}, {}", self.field, self.path, self.reason)
+    }
+}
+
+impl ResponseError for PathError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::BadRequest().json(self)
+    }
+}
+
+/// request with file path parameters
+pub trait PathFields {
+    /// field name and value of every file path
+    fn path_fields(&mut self) -> Vec<(String, &mut String)>;
+}
+
+/// keeps file path parameters inside the configured storage roots
+#[derive(Debug)]
+pub struct Sandbox {
+    roots: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    pub fn new(roots: &[String]) -> Result<Self, String> {
+        let roots = roots
+            .iter()
+            .map(|x| fs::canonicalize(x).map_err(|e| format!("storage root {} is invalid: {:?}", x, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if roots.is_empty() {
+            warn!("storage_roots is not set, file paths of requests are not checked");
+        }
+
+        Ok(Self { roots })
+    }
+
+    /// canonical form of `path`, missing trailing components are kept as is
+    fn canonicalize(field: &str, path: &Path) -> Result<PathBuf, PathError> {
+        let error = |reason: &str| PathError::new(field, path.to_string_lossy(), reason);
+
+        for ancestor in path.ancestors() {
+            match fs::canonicalize(ancestor) {
+                Ok(canonical) => return Ok(canonical.join(path.strip_prefix(ancestor).unwrap())),
+                // a dangling symlink would be followed once the file is created
+                Err(_) if fs::symlink_metadata(ancestor).is_ok() => return Err(error("broken symlink")),
+                Err(_) => {}
+            }
+        }
+
+        Err(error("no existing parent directory"))
+    }
+
+    /// canonical form of `path` if it's inside storage roots
+    pub fn check(&self, field: &str, path: &str) -> Result<PathBuf, PathError> {
+        let error = |reason: &str| PathError::new(field, path, reason);
+        let lexical = Path::new(path);
+        if self.roots.is_empty() {
+            return Ok(lexical.to_path_buf());
+        }
+
+        if !lexical.is_absolute() {
+            return Err(error("path must be absolute"));
+        }
+        if lexical.components().any(|x| x == Component::ParentDir) {
+            return Err(error("path traversal is not allowed"));
+        }
+
+        let canonical = Self::canonicalize(field, lexical)?;
+        if self.roots.iter().any(|x| canonical.starts_with(x)) {
+            return Ok(canonical);
+        }
+
+        if self.roots.iter().any(|x| lexical.starts_with(x)) {
+            Err(error("symlink escapes storage roots"))
+        } else {
+            Err(error("outside storage roots"))
+        }
+    }
+
+    /// check every path of `data`, they're replaced with their canonical form
+    pub fn check_fields<T: PathFields>(&self, data: &mut T) -> Result<(), PathError> {
+        for (field, path) in data.path_fields() {
+            let canonical = self.check(&field, path)?;
+            *path = canonical.to_string_lossy().into_owned();
+        }
+
+        Ok(())
+    }
+}
+
+/// name of an uploaded file, must not leave the upload dir
+pub fn check_file_name(file_name: Option<&str>) -> Result<&str, PathError> {
+    let file_name = file_name.ok_or_else(|| PathError::new("filename", "", "file name is missing"))?;
+    let mut components = Path::new(file_name).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(file_name),
+        _ => Err(PathError::new("filename", file_name, "must be a plain file name")),
+    }
+}
+
+macro_rules! path_fields {
+    ($t:ty, $($field:ident),+) => {
+        impl PathFields for $t {
+            fn path_fields(&mut self) -> Vec<(String, &mut String)> {
+                vec![$((stringify!($field).to_string(), &mut self.$field)),+]
+            }
+        }
+    };
+}
+
+path_fields!(ClearCacheData, cache_path);
+path_fields!(SealPreCommitPhase1Data, cache_path, in_path, out_path);
+path_fields!(SealPreCommitPhase2Data, cache_path, out_path);
+path_fields!(SealCommitPhase1Data, cache_path, replica_path);
+path_fields!(GetUnsealedRangeData, cache_path, sealed_path, output_path);
+path_fields!(GeneratePieceCommitmentData, source);
+path_fields!(AddPieceData, source, target);
+path_fields!(WriteAndPreprocessData, source, target);
+
+impl PathFields for WebPrivateReplicas {
+    fn path_fields(&mut self) -> Vec<(String, &mut String)> {
+        self.0
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(i, x)| {
+                let info = &mut x.private_replica_info;
+                vec![
+                    (format!("replicas[{}].cache_dir", i), &mut info.cache_dir),
+                    (format!("replicas[{}].replica_path", i), &mut info.replica_path),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl PathFields for GenerateWinningPostData {
+    fn path_fields(&mut self) -> Vec<(String, &mut String)> {
+        self.replicas.path_fields()
+    }
+}
+
+impl PathFields for GenerateWindowPostJobData {
+    fn path_fields(&mut self) -> Vec<(String, &mut String)> {
+        self.replicas.path_fields()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// a fresh dir under the system temp dir, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("webapi-sandbox-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(fs::canonicalize(dir).unwrap())
+        }
+
+        fn join(&self, path: &str) -> String {
+            self.0.join(path).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sandbox(roots: &[String]) -> Sandbox {
+        Sandbox::new(roots).unwrap()
+    }
+
+    fn reason(r: Result<PathBuf, PathError>) -> String {
+        r.unwrap_err().reason
+    }
+
+    #[test]
+    fn path_inside_root() {
+        let dir = TempDir::new("inside");
+        fs::create_dir(dir.join("sectors")).unwrap();
+        let sandbox = sandbox(&[dir.join("sectors")]);
+
+        let path = sandbox.check("out_path", &dir.join("sectors/s-t01000-1")).unwrap();
+        assert_eq!(path, dir.0.join("sectors/s-t01000-1"));
+    }
+
+    #[test]
+    fn no_roots_allow_everything() {
+        let sandbox = sandbox(&[]);
+        assert_eq!(sandbox.check("out_path", "../x").unwrap(), PathBuf::from("../x"));
+    }
+
+    #[test]
+    fn parent_dir_traversal() {
+        let dir = TempDir::new("traversal");
+        let sandbox = sandbox(&[dir.join("")]);
+
+        let r = sandbox.check("out_path", &dir.join("a/../../etc/passwd"));
+        assert_eq!(reason(r), "path traversal is not allowed");
+    }
+
+    #[test]
+    fn relative_path() {
+        let dir = TempDir::new("relative");
+        let sandbox = sandbox(&[dir.join("")]);
+
+        assert_eq!(reason(sandbox.check("out_path", "sectors/x")), "path must be absolute");
+    }
+
+    #[test]
+    fn symlink_out_of_root() {
+        let dir = TempDir::new("symlink");
+        let outside = TempDir::new("symlink-outside");
+        fs::create_dir(dir.join("sectors")).unwrap();
+        symlink(&outside.0, dir.join("sectors/link")).unwrap();
+        let sandbox = sandbox(&[dir.join("sectors")]);
+
+        let r = sandbox.check("out_path", &dir.join("sectors/link/file"));
+        assert_eq!(reason(r), "symlink escapes storage roots");
+    }
+
+    #[test]
+    fn dangling_symlink() {
+        let dir = TempDir::new("dangling");
+        symlink(dir.join("missing"), dir.join("dangling")).unwrap();
+        let sandbox = sandbox(&[dir.join("")]);
+
+        assert_eq!(
+            reason(sandbox.check("out_path", &dir.join("dangling"))),
+            "broken symlink"
+        );
+        assert_eq!(
+            reason(sandbox.check("out_path", &dir.join("dangling/file"))),
+            "broken symlink"
+        );
+    }
+
+    #[test]
+    fn missing_parent_dir() {
+        let dir = TempDir::new("missing");
+        let sandbox = sandbox(&[dir.join("")]);
+
+        // dirs which don't exist yet are kept as is
+        let path = sandbox.check("out_path", &dir.join("new/sub/file")).unwrap();
+        assert_eq!(path, dir.0.join("new/sub/file"));
+
+        let r = sandbox.check("out_path", "/webapi-sandbox-not-exist/file");
+        assert_eq!(reason(r), "outside storage roots");
+    }
+
+    #[test]
+    fn root_is_not_a_string_prefix() {
+        let dir = TempDir::new("prefix");
+        fs::create_dir(dir.join("sec")).unwrap();
+        fs::create_dir(dir.join("sectors")).unwrap();
+        let sandbox = sandbox(&[dir.join("sec")]);
+
+        let r = sandbox.check("out_path", &dir.join("sectors/file"));
+        assert_eq!(reason(r), "outside storage roots");
+        assert!(sandbox.check("out_path", &dir.join("sec/file")).is_ok());
+    }
+
+    #[test]
+    fn check_fields_rewrites_paths() {
+        let dir = TempDir::new("fields");
+        fs::create_dir(dir.join("cache")).unwrap();
+        symlink(dir.join("cache"), dir.join("link")).unwrap();
+        let sandbox = sandbox(&[dir.join("")]);
+
+        let mut data = ClearCacheData {
+            sector_size: 2048,
+            cache_path: dir.join("link/s-t01000-1"),
+        };
+        sandbox.check_fields(&mut data).unwrap();
+        assert_eq!(data.cache_path, dir.join("cache/s-t01000-1"));
+    }
+
+    #[test]
+    fn file_name() {
+        assert_eq!(check_file_name(Some("piece.dat")).unwrap(), "piece.dat");
+
+        for name in &["", ".", "..", "../piece.dat", "a/piece.dat", "/etc/passwd"] {
+            assert!(check_file_name(Some(name)).is_err(), "{} is accepted", name);
+        }
+        assert_eq!(check_file_name(None).unwrap_err().reason, "file name is missing");
+    }
+}
@@ -1,32 +1,32 @@
 use crate::seal_data::*;
-use crate::system::{ServState, WorkerProp};
+use crate::registry::{draining_response, submit_job, submit_prop, ServState, WorkerProp};
 use crate::types::WebPieceInfo;
 use actix_web::web::{Data, Json, Payload};
-use actix_web::{Error, HttpRequest, HttpResponse};
+use actix_web::{Error, HttpRequest, HttpResponse, ResponseError};
 use bytes::BytesMut;
 use filecoin_proofs_api::{seal, PieceInfo};
 use futures_util::StreamExt;
 use log::*;
-use serde_json::json;
+use serde_json::{json, Value};
 use std::fs::OpenOptions;
-use std::io;
 use std::path::Path;
-use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
 use std::time::Instant;
 
-pub async fn clear_cache(_req: HttpRequest, data: Json<ClearCacheData>) -> HttpResponse {
+pub async fn clear_cache(_req: HttpRequest, state: Data<ServState>, data: Json<ClearCacheData>) -> HttpResponse {
     trace!("clear_cache");
 
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+
     let r = seal::clear_cache(data.sector_size, Path::new(&data.cache_path));
 
     HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
 }
 
-pub async fn seal_pre_commit_phase1(data: Json<SealPreCommitPhase1Data>) -> HttpResponse {
-    trace!("seal_pre_commit_phase1");
-
+/// body of PC1 job, runs in worker process
+pub fn run_seal_pre_commit_phase1(data: SealPreCommitPhase1Data) -> Value {
     let piece_infos: Vec<PieceInfo> = data.piece_infos.iter().map(|x| x.as_object()).collect();
 
     let r = seal::seal_pre_commit_phase1(
@@ -40,15 +40,46 @@ pub async fn seal_pre_commit_phase1(data: Json<SealPreCommitPhase1Data>) -> Http
         &piece_infos[..],
     );
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    trace!("seal_pre_commit_phase1 finished: {:?}", r);
+    json!(r.map_err(|e| format!("{:?}", e)))
 }
 
-pub async fn seal_pre_commit_phase2(data: Json<SealPreCommitPhase2Data>) -> HttpResponse {
-    trace!("seal_pre_commit_phase2");
+pub async fn seal_pre_commit_phase1(
+    req: HttpRequest,
+    state: Data<ServState>,
+    data: Json<SealPreCommitPhase1Data>,
+) -> HttpResponse {
+    trace!("seal_pre_commit_phase1");
+
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+    let options = std::mem::take(&mut data.options);
+    submit_job(&state, &req, "PC1", json!(data), options)
+}
 
-    let r = seal::seal_pre_commit_phase2(data.phase1_output.clone(), &data.cache_path, &data.out_path);
+/// body of PC2 job, runs in worker process
+pub fn run_seal_pre_commit_phase2(data: SealPreCommitPhase2Data) -> Value {
+    let r = seal::seal_pre_commit_phase2(data.phase1_output, &data.cache_path, &data.out_path);
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    trace!("seal_pre_commit_phase2 finished: {:?}", r);
+    json!(r.map_err(|e| format!("{:?}", e)))
+}
+
+pub async fn seal_pre_commit_phase2(
+    req: HttpRequest,
+    state: Data<ServState>,
+    data: Json<SealPreCommitPhase2Data>,
+) -> HttpResponse {
+    trace!("seal_pre_commit_phase2");
+
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+    let options = std::mem::take(&mut data.options);
+    submit_job(&state, &req, "PC2", json!(data), options)
 }
 
 pub async fn compute_comm_d(data: Json<ComputeCommDData>) -> HttpResponse {
@@ -61,70 +92,130 @@ pub async fn compute_comm_d(data: Json<ComputeCommDData>) -> HttpResponse {
     HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
 }
 
-pub async fn seal_commit_phase1(state: Data<Arc<Mutex<ServState>>>, data: Json<SealCommitPhase1Data>) -> HttpResponse {
-    trace!("seal_commit_phase1: {:?}", data);
+/// body of C1 job, runs in worker process
+pub fn run_seal_commit_phase1(data: SealCommitPhase1Data) -> Value {
+    let piece_infos: Vec<PieceInfo> = data.piece_infos.iter().map(|x| x.as_object()).collect();
 
-    let (tx, rx) = channel();
-    let handle: JoinHandle<()> = thread::spawn(move || {
-        let piece_infos: Vec<PieceInfo> = data.piece_infos.iter().map(|x| x.as_object()).collect();
+    let r = seal::seal_commit_phase1(
+        &data.cache_path,
+        &data.replica_path,
+        data.prover_id,
+        data.sector_id,
+        data.ticket,
+        data.seed,
+        data.pre_commit.clone(),
+        &piece_infos[..],
+    );
 
-        let r = seal::seal_commit_phase1(
-            &data.cache_path,
-            &data.replica_path,
-            data.prover_id,
-            data.sector_id,
-            data.ticket,
-            data.seed,
-            data.pre_commit.clone(),
-            &piece_infos[..],
-        );
+    trace!("seal_commit_phase1 finished: {:?}", r);
+    json!(r.map_err(|e| format!("{:?}", e)))
+}
 
-        trace!("seal_commit_phase1 finished: {:?}", r);
-        if let Err(e) = tx.send(json!(r.map_err(|e| format!("{:?}", e)))) {
-            error!("{:?}", e);
-        }
-    });
+pub async fn seal_commit_phase1(
+    req: HttpRequest,
+    state: Data<ServState>,
+    data: Json<SealCommitPhase1Data>,
+) -> HttpResponse {
+    trace!("seal_commit_phase1: {:?}", data);
 
-    let prop = WorkerProp::new("C1".to_string(), handle, rx);
-    let response = state.lock().unwrap().enqueue(prop);
-    HttpResponse::Ok().json(response)
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+    let options = std::mem::take(&mut data.options);
+    submit_job(&state, &req, "C1", json!(data), options)
 }
 
-pub async fn seal_commit_phase2(
-    state: Data<Arc<Mutex<ServState>>>,
-    mut payload: Payload,
-) -> Result<HttpResponse, Error> {
-    if !state.lock().unwrap().job_available("C2") {
-        return Ok(HttpResponse::TooManyRequests().finish());
+/// body of C2 job, runs in worker process
+pub fn run_seal_commit_phase2(data: SealCommitPhase2Data) -> Value {
+    let start = Instant::now();
+    let r = seal::seal_commit_phase2(data.phase1_output.clone(), data.prover_id, data.sector_id);
+
+    debug!("seal_commit_phase2 finished in {} secs", start.elapsed().as_secs());
+    if !r.is_ok() {
+        warn!("seal_commit_phase2 calc error: {:?}", r);
     }
 
+    json!(r.map_err(|e| format!("{:?}", e)))
+}
+
+/// read whole request body, C2 inputs are larger than default json limit
+async fn read_payload(mut payload: Payload) -> Result<BytesMut, Error> {
     let mut bytes = BytesMut::new();
     while let Some(item) = payload.next().await {
         bytes.extend_from_slice(&item?);
     }
 
-    let data_len = bytes.len();
-    let data: SealCommitPhase2Data = serde_json::from_slice(bytes.as_ref())?;
-    debug!("seal_commit_phase2, data len: {}", data_len);
+    Ok(bytes)
+}
+
+pub async fn seal_commit_phase2(
+    req: HttpRequest,
+    state: Data<ServState>,
+    payload: Payload,
+) -> Result<HttpResponse, Error> {
+    // checked first, so clients of a draining server get 503 rather than 429
+    if state.is_draining() {
+        return Ok(draining_response());
+    }
+
+    // only reject when C2 queue is full, otherwise the job waits for a free slot
+    if !state.queue_available("C2") {
+        return Ok(HttpResponse::TooManyRequests().finish());
+    }
+
+    let bytes = read_payload(payload).await?;
+    let input: Value = serde_json::from_slice(bytes.as_ref())?;
+    debug!("seal_commit_phase2, data len: {}", bytes.len());
+
+    // phase1_output may refer to a C1 job, C2 then starts once C1 is done
+    if input.pointer("/phase1_output/job").is_some() {
+        let mut data: SealCommitPhase2ChainData = serde_json::from_value(input)?;
+        let options = std::mem::take(&mut data.options);
+        let prop = WorkerProp::new("C2".to_string(), json!(data))
+            .with_options(options)
+            .with_request(&req)
+            .with_dependency(data.phase1_output.job, "phase1_output");
 
-    let (tx, rx) = channel();
-    let handle: JoinHandle<()> = thread::spawn(move || {
-        let start = Instant::now();
-        let r = seal::seal_commit_phase2(data.phase1_output.clone(), data.prover_id, data.sector_id);
+        return Ok(submit_prop(&state, prop));
+    }
 
-        debug!("seal_commit_phase2 finished in {} secs", start.elapsed().as_secs());
-        if !r.is_ok() {
-            warn!("seal_commit_phase2 calc error: {:?}", r);
-        }
+    let mut data: SealCommitPhase2Data = serde_json::from_value(input)?;
+    let options = std::mem::take(&mut data.options);
+    Ok(submit_job(&state, &req, "C2", json!(data), options))
+}
 
-        if let Err(e) = tx.send(json!(r.map_err(|e| format!("{:?}", e)))) {
-            error!("seal_commit_phase2 send error: {:?}", e);
-        }
-    });
+/// submit many C2 at once, every item is scheduled as a separate C2 job
+pub async fn seal_commit_phase2_batch(
+    req: HttpRequest,
+    state: Data<ServState>,
+    payload: Payload,
+) -> Result<HttpResponse, Error> {
+    if state.is_draining() {
+        return Ok(draining_response());
+    }
 
-    let prop = WorkerProp::new("C2".to_string(), handle, rx);
-    let response = state.lock().unwrap().enqueue(prop);
-    Ok(HttpResponse::Ok().json(response))
+    let bytes = read_payload(payload).await?;
+    let items: Vec<SealCommitPhase2Data> = serde_json::from_slice(bytes.as_ref())?;
+    debug!(
+        "seal_commit_phase2_batch, {} items, data len: {}",
+        items.len(),
+        bytes.len()
+    );
+
+    let props = items
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut data)| {
+            let options = std::mem::take(&mut data.options);
+            WorkerProp::new("C2".to_string(), json!(data))
+                .with_options(options)
+                .with_request(&req)
+                .with_batch_index(index)
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(state.enqueue_batch(props)))
 }
 
 pub async fn verify_seal(data: Json<VerifySealData>) -> HttpResponse {
@@ -163,9 +254,8 @@ pub async fn verify_batch_seal(data: Json<VerifyBatchSealData>) -> HttpResponse
     HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
 }
 
-pub async fn get_unsealed_range(data: Json<GetUnsealedRangeData>) -> HttpResponse {
-    trace!("get_unsealed_range");
-
+/// body of unseal job, runs in worker process
+pub fn run_get_unsealed_range(data: GetUnsealedRangeData) -> Value {
     let r = seal::get_unsealed_range(
         data.registered_proof,
         &data.cache_path,
@@ -179,46 +269,96 @@ pub async fn get_unsealed_range(data: Json<GetUnsealedRangeData>) -> HttpRespons
         data.num_bytes,
     );
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    trace!("get_unsealed_range finished: {:?}", r);
+    json!(r.map_err(|e| format!("{:?}", e)))
 }
 
-pub async fn generate_piece_commitment(data: Json<GeneratePieceCommitmentData>) -> io::Result<HttpResponse> {
+pub async fn get_unsealed_range(
+    req: HttpRequest,
+    state: Data<ServState>,
+    data: Json<GetUnsealedRangeData>,
+) -> HttpResponse {
+    trace!("get_unsealed_range");
+
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+    let options = std::mem::take(&mut data.options);
+    submit_job(&state, &req, "Unseal", json!(data), options)
+}
+
+pub async fn generate_piece_commitment(
+    state: Data<ServState>,
+    data: Json<GeneratePieceCommitmentData>,
+) -> Result<HttpResponse, Error> {
     trace!("generate_piece_commitment");
 
+    let mut data = data.into_inner();
+    state.sandbox().check_fields(&mut data)?;
+
     let source = OpenOptions::new().read(true).open(&data.source)?;
     let r = seal::generate_piece_commitment(data.registered_proof, source, data.piece_size);
 
     Ok(HttpResponse::Ok().json(r.map(|x| WebPieceInfo::from_object(x)).map_err(|e| format!("{:?}", e))))
 }
 
-pub async fn add_piece(data: Json<AddPieceData>) -> io::Result<HttpResponse> {
+/// body of add piece job, runs in worker process
+pub fn run_add_piece(data: AddPieceData) -> Value {
+    let r = (|| {
+        let source = OpenOptions::new().read(true).open(&data.source)?;
+        let target = OpenOptions::new().write(true).open(&data.target)?;
+        seal::add_piece(
+            data.registered_proof,
+            source,
+            target,
+            data.piece_size,
+            &data.piece_lengths[..],
+        )
+    })();
+
+    trace!("add_piece finished: {:?}", r);
+    json!(r
+        .map(|(x, y)| AddPieceOutput::from_object((x, y)))
+        .map_err(|e| format!("{:?}", e)))
+}
+
+pub async fn add_piece(req: HttpRequest, state: Data<ServState>, data: Json<AddPieceData>) -> HttpResponse {
     trace!("add_piece");
 
-    let source = OpenOptions::new().read(true).open(&data.source)?;
-    let target = OpenOptions::new().write(true).open(&data.target)?;
-    let r = seal::add_piece(
-        data.registered_proof,
-        source,
-        target,
-        data.piece_size,
-        &data.piece_lengths[..],
-    );
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+    let options = std::mem::take(&mut data.options);
+    submit_job(&state, &req, "AddPiece", json!(data), options)
+}
 
-    Ok(HttpResponse::Ok().json(
-        r.map(|(x, y)| AddPieceOutput::from_object((x, y)))
-            .map_err(|e| format!("{:?}", e)),
-    ))
+/// body of write and preprocess job, runs in worker process
+pub fn run_write_and_preprocess(data: WriteAndPreprocessData) -> Value {
+    let r = (|| {
+        let source = OpenOptions::new().read(true).open(&data.source)?;
+        let target = OpenOptions::new().write(true).open(&data.target)?;
+        seal::write_and_preprocess(data.registered_proof, source, target, data.piece_size)
+    })();
+
+    trace!("write_and_preprocess finished: {:?}", r);
+    json!(r
+        .map(|(x, y)| WriteAndPreprocessOutput::from_object((x, y)))
+        .map_err(|e| format!("{:?}", e)))
 }
 
-pub async fn write_and_preprocess(data: Json<WriteAndPreprocessData>) -> io::Result<HttpResponse> {
+pub async fn write_and_preprocess(
+    req: HttpRequest,
+    state: Data<ServState>,
+    data: Json<WriteAndPreprocessData>,
+) -> HttpResponse {
     trace!("write_and_preprocess");
 
-    let source = OpenOptions::new().read(true).open(&data.source)?;
-    let target = OpenOptions::new().write(true).open(&data.target)?;
-    let r = seal::write_and_preprocess(data.registered_proof, source, target, data.piece_size);
-
-    Ok(HttpResponse::Ok().json(
-        r.map(|(x, y)| WriteAndPreprocessOutput::from_object((x, y)))
-            .map_err(|e| format!("{:?}", e)),
-    ))
+    let mut data = data.into_inner();
+    if let Err(e) = state.sandbox().check_fields(&mut data) {
+        return e.error_response();
+    }
+    let options = std::mem::take(&mut data.options);
+    submit_job(&state, &req, "WriteAndPreprocess", json!(data), options)
 }
@@ -22,6 +22,8 @@ pub struct SealPreCommitPhase1Data {
     pub sector_id: SectorId,
     pub ticket: Ticket,
     pub piece_infos: Vec<WebPieceInfo>,
+    #[serde(flatten)]
+    pub options: JobOptions,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -29,6 +31,8 @@ pub struct SealPreCommitPhase2Data {
     pub phase1_output: SealPreCommitPhase1Output,
     pub cache_path: String,
     pub out_path: String,
+    #[serde(flatten)]
+    pub options: JobOptions,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -47,6 +51,8 @@ pub struct SealCommitPhase1Data {
     pub seed: Ticket,
     pub pre_commit: SealPreCommitPhase2Output,
     pub piece_infos: Vec<WebPieceInfo>,
+    #[serde(flatten)]
+    pub options: JobOptions,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -54,6 +60,24 @@ pub struct SealCommitPhase2Data {
     pub phase1_output: SealCommitPhase1Output,
     pub prover_id: ProverId,
     pub sector_id: SectorId,
+    #[serde(flatten)]
+    pub options: JobOptions,
+}
+
+/// reference to output of another job on this server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobRef {
+    pub job: u64,
+}
+
+/// C2 whose phase1 output is taken from a C1 job once it's finished
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SealCommitPhase2ChainData {
+    pub phase1_output: JobRef,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    #[serde(flatten)]
+    pub options: JobOptions,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -92,6 +116,8 @@ pub struct GetUnsealedRangeData {
     pub ticket: Ticket,
     pub offset: UnpaddedByteIndex,
     pub num_bytes: UnpaddedBytesAmount,
+    #[serde(flatten)]
+    pub options: JobOptions,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -108,6 +134,8 @@ pub struct AddPieceData {
     pub target: String,
     pub piece_size: UnpaddedBytesAmount,
     pub piece_lengths: Vec<UnpaddedBytesAmount>,
+    #[serde(flatten)]
+    pub options: JobOptions,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -128,6 +156,8 @@ pub struct WriteAndPreprocessData {
     pub source: String,
     pub target: String,
     pub piece_size: UnpaddedBytesAmount,
+    #[serde(flatten)]
+    pub options: JobOptions,
 }
 
 pub type WriteAndPreprocessOutput = AddPieceOutput;
@@ -0,0 +1,305 @@
+use crate::polling::{JobAttempt, PollingError, PollingState};
+use crate::types::JobOptions;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+    Interrupted,
+}
+
+impl JobStatus {
+    /// status of a job which finished with `result`, proofs report errors as `{"Err": ...}`
+    pub fn of_result(result: &Value) -> Self {
+        if result.get("Err").is_some() {
+            JobStatus::Failed
+        } else {
+            JobStatus::Done
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum CallbackStatus {
+    Pending,
+    Delivering,
+    Delivered { attempts: u32 },
+    Failed { attempts: u32, error: String },
+}
+
+/// input `field` of a job is filled with output of job `token`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobDependency {
+    pub token: u64,
+    pub field: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobRecord {
+    pub token: u64,
+    pub kind: String,
+    pub digest: String,
+    pub status: JobStatus,
+    pub result: Option<Value>,
+    #[serde(default)]
+    pub error: Option<PollingError>,
+    pub create_time: SystemTime,
+    #[serde(default)]
+    pub start_time: Option<SystemTime>,
+    pub finish_time: Option<SystemTime>,
+    /// who submitted the job
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    #[serde(default)]
+    pub sector_id: Option<u64>,
+    #[serde(default)]
+    pub prover_id: Option<String>,
+    #[serde(default)]
+    pub failed_attempts: Vec<JobAttempt>,
+    #[serde(default)]
+    pub dependency: Option<JobDependency>,
+    #[serde(default)]
+    pub options: JobOptions,
+    #[serde(default)]
+    pub callback: Option<CallbackStatus>,
+}
+
+/// summary of a job in job listing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobInfo {
+    pub token: u64,
+    pub kind: String,
+    pub status: JobStatus,
+    pub create_time: SystemTime,
+    pub last_query: Option<SystemTime>,
+    pub duration_secs: Option<u64>,
+    pub owner: Option<String>,
+    pub sector_id: Option<u64>,
+    pub prover_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_attempts: Vec<JobAttempt>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depends_on: Option<u64>,
+}
+
+impl JobRecord {
+    pub fn new(token: u64, kind: String, digest: String, create_time: SystemTime) -> Self {
+        Self {
+            token,
+            kind,
+            digest,
+            status: JobStatus::Running,
+            result: None,
+            error: None,
+            create_time,
+            start_time: None,
+            finish_time: None,
+            owner: None,
+            idempotency_key: None,
+            sector_id: None,
+            prover_id: None,
+            failed_attempts: vec![],
+            dependency: None,
+            options: JobOptions::default(),
+            callback: None,
+        }
+    }
+
+    pub fn info(&self) -> JobInfo {
+        let duration_secs = match (self.start_time, self.finish_time) {
+            (Some(start), Some(finish)) => finish.duration_since(start).ok().map(|x| x.as_secs()),
+            _ => None,
+        };
+
+        JobInfo {
+            token: self.token,
+            kind: self.kind.clone(),
+            status: self.status,
+            create_time: self.create_time,
+            last_query: None,
+            duration_secs,
+            owner: self.owner.clone(),
+            sector_id: self.sector_id,
+            prover_id: self.prover_id.clone(),
+            failed_attempts: self.failed_attempts.clone(),
+            depends_on: self.dependency.as_ref().map(|x| x.token),
+        }
+    }
+
+    pub fn state(&self) -> PollingState {
+        match (&self.status, &self.result, &self.error) {
+            (JobStatus::Done, Some(r), _) | (JobStatus::Failed, Some(r), None) => PollingState::Done(r.clone()),
+            (JobStatus::Failed, _, Some(e)) => PollingState::Error(e.clone()),
+            (JobStatus::Interrupted, _, _) => PollingState::Interrupted,
+            (JobStatus::Cancelled, _, _) => PollingState::Cancelled,
+            _ => PollingState::Error(PollingError::NotExist),
+        }
+    }
+}
+
+/// On-disk job registry, one json file per job under `{state_dir}/jobs`
+#[derive(Debug)]
+pub struct JobStore {
+    dir: PathBuf,
+    history: PathBuf,
+    groups: PathBuf,
+    next_token: PathBuf,
+    // last saved value of `next_token`, it only grows
+    saved_token: Mutex<u64>,
+}
+
+/// write to a temp file first, so a crash never leaves a half written file
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+impl JobStore {
+    pub fn open<P: Into<PathBuf>>(state_dir: P) -> io::Result<Self> {
+        let state_dir = state_dir.into();
+        let dir = state_dir.join("jobs");
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            history: state_dir.join("history.json"),
+            groups: state_dir.join("groups.json"),
+            next_token: state_dir.join("next_token.json"),
+            saved_token: Mutex::new(0),
+        })
+    }
+
+    fn record_path(&self, token: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", token))
+    }
+
+    pub fn load(&self) -> io::Result<Vec<JobRecord>> {
+        let mut records = vec![];
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map(|x| x != "json").unwrap_or(true) {
+                continue;
+            }
+
+            match fs::read(&path).map(|x| serde_json::from_slice::<JobRecord>(&x)) {
+                Ok(Ok(mut record)) => {
+                    // older records list proofs which returned `Err` as done
+                    if let (JobStatus::Done, Some(r)) = (record.status, &record.result) {
+                        record.status = JobStatus::of_result(r);
+                    }
+                    records.push(record);
+                }
+                Ok(Err(e)) => warn!("skip broken job record {:?}: {:?}", path, e),
+                Err(e) => warn!("read job record {:?} failed: {:?}", path, e),
+            }
+        }
+
+        Ok(records)
+    }
+
+    pub fn save(&self, record: &JobRecord) -> io::Result<()> {
+        write_file(&self.record_path(record.token), &serde_json::to_vec(record)?)
+    }
+
+    pub fn remove(&self, token: u64) -> io::Result<()> {
+        match fs::remove_file(self.record_path(token)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// job durations in seconds, keyed by job kind and sector size
+    pub fn load_history(&self) -> io::Result<HashMap<String, Vec<u64>>> {
+        match fs::read(&self.history) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_history(&self, history: &HashMap<String, Vec<u64>>) -> io::Result<()> {
+        write_file(&self.history, &serde_json::to_vec(history)?)
+    }
+
+    /// job tokens of batch submissions, keyed by group token
+    pub fn load_groups(&self) -> io::Result<HashMap<u64, Vec<u64>>> {
+        match fs::read(&self.groups) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_groups(&self, groups: &HashMap<u64, Vec<u64>>) -> io::Result<()> {
+        write_file(&self.groups, &serde_json::to_vec(groups)?)
+    }
+
+    /// lowest token never handed out, 0 if nothing is saved yet
+    pub fn load_next_token(&self) -> io::Result<u64> {
+        let token = match fs::read(&self.next_token) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        *self.saved_token.lock().unwrap() = token;
+
+        Ok(token)
+    }
+
+    /// persist `token` as the lowest token never handed out, smaller values are ignored
+    pub fn save_next_token(&self, token: u64) -> io::Result<()> {
+        let mut saved = self.saved_token.lock().unwrap();
+        if token <= *saved {
+            return Ok(());
+        }
+
+        write_file(&self.next_token, &serde_json::to_vec(&token)?)?;
+        *saved = token;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn err_result_is_failed() {
+        let dir = std::env::temp_dir().join(format!("webapi-store-{}", std::process::id()));
+        let store = JobStore::open(&dir).unwrap();
+
+        let mut record = JobRecord::new(1, "C2".to_string(), String::new(), SystemTime::now());
+        record.status = JobStatus::Done;
+        record.result = Some(json!({"Err": "proof failed"}));
+        store.save(&record).unwrap();
+
+        let records = store.load().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(records[0].status, JobStatus::Failed);
+        assert_eq!(records[0].info().status, JobStatus::Failed);
+        // clients still get the error returned by the proof
+        match records[0].state() {
+            PollingState::Done(r) => assert_eq!(r, json!({"Err": "proof failed"})),
+            x => panic!("unexpected state {:?}", x),
+        }
+    }
+}
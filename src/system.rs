@@ -1,226 +1,189 @@
-use crate::config::Config;
 use crate::polling::*;
+use crate::registry::{submit_job, ListJobsParam, ServState};
+use crate::sandbox::{check_file_name, PathError};
+use crate::types::JobOptions;
 use actix_multipart::Multipart;
-use actix_web::web::{self, Data, Json};
-use actix_web::{Error, HttpResponse};
-use futures::stream::{StreamExt, TryStreamExt};
-use lazy_static::lazy_static;
-use libc::pthread_cancel;
+use actix_rt::time::delay_for;
+use actix_web::web::{self, Bytes, Data, Json, Path as WebPath};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use log::*;
-use serde_json::{json, Value};
-use std::collections::HashMap;
-use std::fmt;
-use std::fmt::Formatter;
+use serde::Deserialize;
+use serde_json::json;
 use std::io::Write;
-use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::mpsc::{channel, Receiver, TryRecvError};
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
-use std::time::{Duration, SystemTime};
-
-lazy_static! {
-    static ref WORKER_TOKEN: AtomicU64 = AtomicU64::new(0);
-    static ref WORKER_INIT: AtomicBool = AtomicBool::new(false);
-}
+use std::time::{Duration, Instant, SystemTime};
 
-pub struct WorkerProp {
-    name: String,
-    handle: JoinHandle<()>,
-    receiver: Receiver<Value>,
-    create_time: SystemTime,
-    last_query: SystemTime,
-}
+/// interval to check job state for long-poll and watch requests
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+/// upper bound of `wait_secs` in long-poll requests
+const MAX_WAIT_SECS: u64 = 300;
+/// a watch stream repeats current state at least this often
+const WATCH_HEARTBEAT: Duration = Duration::from_secs(10);
 
-impl fmt::Debug for WorkerProp {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        writeln!(f, "name: {:#?}", self.name)?;
-        writeln!(f, "create_time: {:#?}", self.create_time)?;
-        writeln!(f, "last_query: {:#?}", self.last_query)?;
-        writeln!(f, "since_query: {}", self.last_query_since_secs())?;
-        writeln!(
-            f,
-            "since_create: {:#?}",
-            SystemTime::now().duration_since(self.create_time).map(|x| x.as_secs())
-        )
-    }
+pub async fn test() -> HttpResponse {
+    trace!("test");
+
+    HttpResponse::Ok().body("Worked!")
 }
 
-impl WorkerProp {
-    pub fn new(name: String, handle: JoinHandle<()>, receiver: Receiver<Value>) -> Self {
-        Self {
-            name,
-            handle,
-            receiver,
-            create_time: SystemTime::now(),
-            last_query: SystemTime::now(),
-        }
-    }
+pub async fn test_polling(req: HttpRequest, state: Data<ServState>) -> HttpResponse {
+    trace!("test polling");
 
-    fn last_query_since_secs(&self) -> u64 {
-        SystemTime::now()
-            .duration_since(self.last_query)
-            .map(|x| x.as_secs())
-            .unwrap_or(0)
-    }
+    // submit time as input, so test jobs are never deduplicated
+    submit_job(&state, &req, "Test", json!(SystemTime::now()), JobOptions::default())
 }
 
-#[derive(Debug)]
-pub struct ServState {
-    workers: HashMap<u64, WorkerProp>,
-    config: Config,
+pub async fn debug_info(state: Data<ServState>) -> HttpResponse {
+    HttpResponse::Ok().body(state.debug_info())
 }
 
-impl ServState {
-    pub fn new(config: Config) -> Self {
-        // NOTE: ensure ServState is init only once
-        assert_eq!(WORKER_INIT.swap(true, Ordering::SeqCst), false);
-
-        Self {
-            workers: HashMap::new(),
-            config,
-        }
-    }
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum QueryStateParam {
+    Token(u64),
+    Wait {
+        token: u64,
+        #[serde(default)]
+        wait_secs: u64,
+    },
+}
 
-    pub fn debug_info(&self) -> String {
-        format!("{:#?}\n", self)
-    }
+/// query job state, with `wait_secs` the request is held until job state changed
+pub async fn query_state(state: Data<ServState>, param: Json<QueryStateParam>) -> HttpResponse {
+    trace!("query_state: {:?}", param);
 
-    pub fn verify_token<S: AsRef<str>>(&self, token: S) -> bool {
-        self.config.allow_tokens.contains(&token.as_ref().to_owned())
-    }
-
-    pub fn job_num<S: AsRef<str>>(&self, name: S) -> u64 {
-        self.workers
-            .iter()
-            .filter(|(_, prop)| prop.name == name.as_ref())
-            .count() as u64
-    }
+    let (token, wait_secs) = match param.into_inner() {
+        QueryStateParam::Token(token) => (token, 0),
+        QueryStateParam::Wait { token, wait_secs } => (token, wait_secs.min(MAX_WAIT_SECS)),
+    };
 
-    pub fn job_limit<S: AsRef<str>>(&self, name: S) -> u64 {
-        *self.config.job_limits.get(name.as_ref()).unwrap_or(&u64::max_value())
+    let deadline = Instant::now() + Duration::from_secs(wait_secs);
+    let initial = state.get(token);
+    let mut response = initial.clone();
+    while !response.is_finished() && !initial.changed(&response) && Instant::now() < deadline {
+        delay_for(WATCH_INTERVAL).await;
+        response = state.get(token);
     }
 
-    pub fn job_available<S: AsRef<str>>(&self, name: S) -> bool {
-        let num = self.job_num(name.as_ref());
-        let limit = self.job_limit(name.as_ref());
+    HttpResponse::Ok().json(response)
+}
 
-        num < limit
+fn watch_event(last: Option<&PollingState>, current: &PollingState) -> &'static str {
+    match current {
+        PollingState::Started(_) => "started",
+        PollingState::Queued { .. } => "queued",
+        PollingState::Pending(_) => match last {
+            Some(PollingState::Pending(_)) => "progress",
+            _ => "started",
+        },
+        PollingState::Done(_) => "done",
+        PollingState::Cancelled => "cancelled",
+        PollingState::Interrupted => "interrupted",
+        PollingState::Removed => "removed",
+        PollingState::Error(_) => "error",
     }
+}
 
-    pub fn enqueue(&mut self, prop: WorkerProp) -> PollingState {
-        let token = WORKER_TOKEN.fetch_add(1, Ordering::SeqCst);
-        self.workers.insert(token, prop);
+/// server-sent events of job state, the stream ends once the job is finished
+pub async fn watch(state: Data<ServState>, token: WebPath<u64>) -> HttpResponse {
+    let token = token.into_inner();
+    trace!("watch: {}", token);
 
-        PollingState::Started(token)
-    }
+    let events = stream::unfold(Some((None, Instant::now())), move |watch| {
+        let state = state.clone();
 
-    pub fn get(&mut self, token: u64) -> PollingState {
-        let state = self
-            .workers
-            .get_mut(&token)
-            .map(|x| {
-                // update query time
-                x.last_query = SystemTime::now();
-
-                match x.receiver.try_recv() {
-                    Ok(r) => PollingState::Done(r),
-                    Err(TryRecvError::Empty) => PollingState::Pending,
-                    Err(TryRecvError::Disconnected) => PollingState::Error(PollingError::Disconnected),
+        async move {
+            let (last, last_sent): (Option<PollingState>, Instant) = watch?;
+            loop {
+                if last.is_some() {
+                    delay_for(WATCH_INTERVAL).await;
                 }
-            })
-            .unwrap_or(PollingState::Error(PollingError::NotExist));
 
-        match &state {
-            PollingState::Done(_) => {
-                debug!("Job {} removed dut to finish", token);
-                self.workers.remove(&token);
-            }
-            PollingState::Error(PollingError::Disconnected) => {
-                debug!("Job {} removed dut to receiver disconnected", token);
-                self.workers.remove(&token);
-            }
-            _ => {}
-        };
+                let current = state.get(token);
+                let changed = last.as_ref().map(|x| x.changed(&current)).unwrap_or(true);
+                if !changed && last_sent.elapsed() < WATCH_HEARTBEAT {
+                    continue;
+                }
 
-        state
-    }
+                let event = format!(
+                    "event: {}\ndata: {}\n\n",
+                    watch_event(last.as_ref(), &current),
+                    serde_json::to_string(&current).unwrap_or_default()
+                );
 
-    pub fn remove(&mut self, token: u64) -> PollingState {
-        if let Some(prop) = self.workers.remove(&token) {
-            debug!("Job {} force removed", token);
-            let pthread_t = prop.handle.into_pthread_t();
+                // send last event and then close the stream
+                let next = if current.is_finished() {
+                    None
+                } else {
+                    Some((Some(current), Instant::now()))
+                };
 
-            unsafe {
-                pthread_cancel(pthread_t);
+                return Some((Ok::<_, Error>(Bytes::from(event)), next));
             }
-
-            return PollingState::Removed;
         }
+    });
 
-        PollingState::Error(PollingError::NotExist)
-    }
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(Box::pin(events))
 }
 
-pub async fn test() -> HttpResponse {
-    trace!("test");
+pub async fn drain(state: Data<ServState>) -> HttpResponse {
+    if state.start_drain() {
+        warn!("drain requested");
+    }
 
-    HttpResponse::Ok().body("Worked!")
+    HttpResponse::Ok().json(state.drain_status())
 }
 
-pub async fn test_polling(state: Data<Arc<Mutex<ServState>>>) -> HttpResponse {
-    trace!("test polling");
-
-    let (tx, rx) = channel();
-    let handle: JoinHandle<()> = thread::spawn(move || {
-        thread::sleep(Duration::from_secs(30));
-        let r = "Ok!!!";
-
-        tx.send(json!(r)).unwrap();
-    });
+pub async fn query_group(state: Data<ServState>, group: Json<u64>) -> HttpResponse {
+    trace!("query_group: {:?}", group);
 
-    let prop = WorkerProp::new("Test".to_string(), handle, rx);
-    let response = state.lock().unwrap().enqueue(prop);
-    HttpResponse::Ok().json(response)
+    match state.get_group(*group) {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => HttpResponse::Ok().json(PollingState::Error(PollingError::NotExist)),
+    }
 }
 
-pub async fn debug_info(state: Data<Arc<Mutex<ServState>>>) -> HttpResponse {
-    let data = {
-        let state = state.lock().unwrap();
-        state.debug_info()
-    };
+pub async fn list_jobs(state: Data<ServState>, param: Json<ListJobsParam>) -> HttpResponse {
+    trace!("list_jobs: {:?}", param);
 
-    HttpResponse::Ok().body(data)
+    let response = state.list_jobs(&param);
+
+    HttpResponse::Ok().json(response)
 }
 
-pub async fn query_state(state: Data<Arc<Mutex<ServState>>>, token: Json<u64>) -> HttpResponse {
-    trace!("query_state: {:?}", token);
+pub async fn ack_job(state: Data<ServState>, token: Json<u64>) -> HttpResponse {
+    trace!("ack_job: {:?}", token);
 
-    let response = state.lock().unwrap().get(*token);
+    let response = state.ack(*token);
 
     HttpResponse::Ok().json(response)
 }
 
-pub async fn remove_job(state: Data<Arc<Mutex<ServState>>>, token: Json<u64>) -> HttpResponse {
+pub async fn remove_job(state: Data<ServState>, token: Json<u64>) -> HttpResponse {
     trace!("remove_job: {:?}", token);
 
-    let response = state.lock().unwrap().remove(*token);
+    let response = state.remove(*token);
 
     HttpResponse::Ok().json(response)
 }
 
-pub async fn upload_file(mut payload: Multipart) -> Result<HttpResponse, Error> {
+pub async fn upload_file(state: Data<ServState>, mut payload: Multipart) -> Result<HttpResponse, Error> {
     trace!("upload_file");
 
     let mut ret_path: Option<String> = None;
 
     // iterate over multipart stream
     while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_type = field.content_disposition().unwrap();
-        let filename = content_type.get_filename().unwrap();
-        let filepath = format!("/tmp/upload/{}", filename);
-        trace!("got file: {}", filepath);
-        ret_path = Some(filepath.clone());
+        let content_type = field
+            .content_disposition()
+            .ok_or_else(|| PathError::new("filename", "", "content disposition is missing"))?;
+        let filename = check_file_name(content_type.get_filename())?;
+        let filepath = state.upload_dir().join(filename);
+        trace!("got file: {:?}", filepath);
+        ret_path = Some(filepath.to_string_lossy().into_owned());
 
         // File::create is blocking operation, use threadpool
         let mut f = web::block(|| std::fs::File::create(filepath)).await.unwrap();
@@ -0,0 +1,247 @@
+use actix_rt::time::delay_for;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use log::*;
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert, RootCertStore, ServerConfig, Session,
+};
+use simple_asn1::{from_der, oid, ASN1Block, BigUint, OID};
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::server::TlsStream;
+
+/// how often cert files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("open {} failed: {:?}", path, e))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// load pem encoded private key and certificate chain
+fn load_key(private_cert: &str, cert_chain: &str) -> Result<CertifiedKey, String> {
+    let certs =
+        pemfile::certs(&mut open(cert_chain)?).map_err(|_| format!("{} is not a valid pem file", cert_chain))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", cert_chain));
+    }
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(private_cert)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(private_cert)?).unwrap_or_default();
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| format!("no pkcs8 or rsa private key found in {}", private_cert))?;
+    let key = sign::any_supported_type(key).map_err(|_| format!("unsupported private key in {}", private_cert))?;
+
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// serves current certificate, reloaded when files are changed
+pub struct CertResolver {
+    private_cert: String,
+    cert_chain: String,
+    /// modify time of both files and key loaded from them
+    current: RwLock<(Option<SystemTime>, Option<SystemTime>, CertifiedKey)>,
+}
+
+impl CertResolver {
+    pub fn new(private_cert: &str, cert_chain: &str) -> Result<Self, String> {
+        let mtimes = (modified(private_cert), modified(cert_chain));
+        let key = load_key(private_cert, cert_chain)?;
+
+        Ok(Self {
+            private_cert: private_cert.to_string(),
+            cert_chain: cert_chain.to_string(),
+            current: RwLock::new((mtimes.0, mtimes.1, key)),
+        })
+    }
+
+    /// reload files if changed, old certificate is kept if new files are invalid
+    pub fn reload(&self) {
+        let mtimes = (modified(&self.private_cert), modified(&self.cert_chain));
+        {
+            let current = self.current.read().unwrap();
+            if (current.0, current.1) == mtimes {
+                return;
+            }
+        }
+
+        let mut current = self.current.write().unwrap();
+        match load_key(&self.private_cert, &self.cert_chain) {
+            Ok(key) => {
+                info!("tls certificate reloaded from {}", self.cert_chain);
+                *current = (mtimes.0, mtimes.1, key);
+            }
+            Err(e) => {
+                warn!("reload tls certificate failed, keep the old one: {}", e);
+                // don't retry until files are changed again
+                current.0 = mtimes.0;
+                current.1 = mtimes.1;
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().2.clone())
+    }
+}
+
+/// rustls server config serving certificates of `resolver`,
+/// clients must present a certificate signed by `client_ca` if set
+pub fn server_config(resolver: Arc<CertResolver>, client_ca: Option<&str>) -> Result<ServerConfig, String> {
+    let verifier = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut open(client_ca)?) {
+                Ok((valid, _)) if valid > 0 => AllowAnyAuthenticatedClient::new(roots),
+                _ => return Err(format!("no valid ca certificate found in {}", client_ca)),
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(verifier);
+    config.cert_resolver = resolver;
+
+    Ok(config)
+}
+
+/// subject common name of verified client certificate, set on requests of that connection
+#[derive(Debug, Clone)]
+pub struct ClientSubject(pub String);
+
+fn string_value(block: &ASN1Block) -> Option<String> {
+    match block {
+        ASN1Block::UTF8String(_, s)
+        | ASN1Block::PrintableString(_, s)
+        | ASN1Block::TeletexString(_, s)
+        | ASN1Block::IA5String(_, s)
+        | ASN1Block::UniversalString(_, s)
+        | ASN1Block::BMPString(_, s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+/// common name in subject of a der encoded certificate
+fn subject_common_name(der: &[u8]) -> Option<String> {
+    let blocks = from_der(der).ok()?;
+    let tbs = match blocks.first()? {
+        ASN1Block::Sequence(_, x) => x.first()?,
+        _ => return None,
+    };
+    let fields = match tbs {
+        ASN1Block::Sequence(_, x) => x,
+        _ => return None,
+    };
+
+    // subject follows serial, signature, issuer and validity, version is optional
+    let skip = match fields.first()? {
+        ASN1Block::Explicit(..) => 1,
+        _ => 0,
+    };
+    let subject = match fields.get(skip + 4)? {
+        ASN1Block::Sequence(_, x) => x,
+        _ => return None,
+    };
+
+    let common_name = oid!(2, 5, 4, 3);
+    subject
+        .iter()
+        .filter_map(|rdn| match rdn {
+            ASN1Block::Set(_, x) => Some(x),
+            _ => None,
+        })
+        .flatten()
+        .find_map(|attr| match attr {
+            ASN1Block::Sequence(_, x) => match (x.first(), x.get(1)) {
+                (Some(ASN1Block::ObjectIdentifier(_, oid)), Some(value)) if *oid == common_name => string_value(value),
+                _ => None,
+            },
+            _ => None,
+        })
+}
+
+/// called on every new connection, remembers who the client certificate belongs to
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    let stream = match conn.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(stream) => stream,
+        None => return,
+    };
+
+    let certs = stream.get_ref().1.get_peer_certificates().unwrap_or_default();
+    if let Some(cert) = certs.first() {
+        match subject_common_name(&cert.0) {
+            Some(subject) => ext.insert(ClientSubject(subject)),
+            None => warn!("no subject common name in client certificate"),
+        }
+    }
+}
+
+/// background task reloading changed certificates
+pub async fn watch(resolver: Arc<CertResolver>) {
+    loop {
+        delay_for(RELOAD_INTERVAL).await;
+        resolver.reload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn common_name(pem: &str) -> Option<String> {
+        let certs = pemfile::certs(&mut pem.as_bytes()).unwrap();
+        subject_common_name(&certs[0].0)
+    }
+
+    #[test]
+    fn v1_cert_without_version() {
+        let name = common_name(include_str!("../testdata/tls/v1.pem"));
+        assert_eq!(name.as_deref(), Some("v1-client"));
+    }
+
+    #[test]
+    fn v3_cert() {
+        let name = common_name(include_str!("../testdata/tls/v3.pem"));
+        assert_eq!(name.as_deref(), Some("v3-client"));
+    }
+
+    #[test]
+    fn multi_rdn_subject() {
+        let name = common_name(include_str!("../testdata/tls/multi_rdn.pem"));
+        assert_eq!(name.as_deref(), Some("multi-rdn-client"));
+
+        // common name in a multi-valued rdn, e.g. `OU=sealing+CN=...`
+        let name = common_name(include_str!("../testdata/tls/multi_value.pem"));
+        assert_eq!(name.as_deref(), Some("multi-value-client"));
+    }
+
+    #[test]
+    fn cert_without_common_name() {
+        assert_eq!(common_name(include_str!("../testdata/tls/no_cn.pem")), None);
+    }
+
+    #[test]
+    fn garbage_input() {
+        assert_eq!(subject_common_name(b""), None);
+        assert_eq!(subject_common_name(b"not a certificate"), None);
+
+        let certs = pemfile::certs(&mut include_str!("../testdata/tls/v3.pem").as_bytes()).unwrap();
+        let der = &certs[0].0;
+        assert_eq!(subject_common_name(&der[..der.len() / 2]), None);
+    }
+}
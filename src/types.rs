@@ -92,3 +92,20 @@ impl WebPublicReplicas {
             .collect()
     }
 }
+
+/// options shared by all requests which submit a job
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct JobOptions {
+    /// final job state is posted to this url
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+    /// key to sign callback body with hmac-sha256
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_secret: Option<String>,
+    /// unix timestamp in seconds, job fails if it can not finish before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>,
+    /// overrides `job_timeouts` of config for this job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
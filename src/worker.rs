@@ -0,0 +1,321 @@
+use crate::polling::PollingError;
+use crate::{post, seal};
+use filecoin_proofs_api::RegisteredSealProof;
+use log::*;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::fs::{self, DirBuilder, File, OpenOptions, Permissions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// name of the hidden subcommand which runs a single job
+pub const WORKER_COMMAND: &str = "worker";
+
+/// how much of worker stderr is reported when it crashed
+const STDERR_TAIL_BYTES: u64 = 4096;
+
+/// prefix of errors caused by bad job input, such errors are never retried
+pub const INVALID_INPUT: &str = "invalid job input";
+
+fn error_value<S: AsRef<str>>(e: S) -> Value {
+    json!(Err::<(), _>(e.as_ref()))
+}
+
+fn parse_input<T: DeserializeOwned>(input: Value) -> Result<T, Value> {
+    serde_json::from_value(input).map_err(|e| error_value(format!("{}: {:?}", INVALID_INPUT, e)))
+}
+
+/// sector size of job input, job durations are grouped by it
+pub fn job_sector_size(input: &Value) -> Option<u64> {
+    let proof = input
+        .get("registered_proof")
+        .or_else(|| input.pointer("/pre_commit/registered_proof"))
+        .or_else(|| input.pointer("/phase1_output/registered_proof"))?;
+
+    serde_json::from_value::<RegisteredSealProof>(proof.clone())
+        .ok()
+        .map(|x| u64::from(x.sector_size()))
+}
+
+/// sector id and hex encoded prover id of job input, if known
+pub fn job_sector(input: &Value) -> (Option<u64>, Option<String>) {
+    let sector_id = input.get("sector_id").and_then(|x| x.as_u64());
+    let prover_id = input
+        .get("prover_id")
+        .and_then(|x| serde_json::from_value::<Vec<u8>>(x.clone()).ok())
+        .map(|x| x.iter().map(|b| format!("{:02x}", b)).collect());
+
+    (sector_id, prover_id)
+}
+
+/// files written by a job, they are rolled back if the job is killed or crashed.
+/// cache dirs are left alone since they're shared by all phases of a sector
+pub fn job_outputs(kind: &str, input: &Value) -> Vec<PathBuf> {
+    let field = match kind {
+        "PC1" => "out_path",
+        "Unseal" => "output_path",
+        "AddPiece" | "WriteAndPreprocess" => "target",
+        _ => return vec![],
+    };
+
+    input
+        .get(field)
+        .and_then(|x| x.as_str())
+        .map(PathBuf::from)
+        .into_iter()
+        .collect()
+}
+
+/// run job in current process, the result is `Result<T, String>` in json
+pub fn run_job(kind: &str, input: Value) -> Value {
+    let r = match kind {
+        "Test" => {
+            thread::sleep(Duration::from_secs(30));
+            Ok(json!("Ok!!!"))
+        }
+        "PC1" => parse_input(input).map(seal::run_seal_pre_commit_phase1),
+        "PC2" => parse_input(input).map(seal::run_seal_pre_commit_phase2),
+        "C1" => parse_input(input).map(seal::run_seal_commit_phase1),
+        "C2" => parse_input(input).map(seal::run_seal_commit_phase2),
+        "WindowPoSt" => parse_input(input).map(post::run_generate_window_post),
+        "Unseal" => parse_input(input).map(seal::run_get_unsealed_range),
+        "AddPiece" => parse_input(input).map(seal::run_add_piece),
+        "WriteAndPreprocess" => parse_input(input).map(seal::run_write_and_preprocess),
+        _ => Err(error_value(format!("unknown job kind {}", kind))),
+    };
+
+    r.unwrap_or_else(|e| e)
+}
+
+/// entry of worker subcommand, read job input from file and write result back
+pub fn worker_main(kind: &str, input: &str, output: &str) -> io::Result<()> {
+    debug!("worker {} started, pid {}", kind, std::process::id());
+
+    let input: Value = serde_json::from_slice(&fs::read(input)?)?;
+    let r = run_job(kind, input);
+
+    let tmp = format!("{}.tmp", output);
+    fs::write(&tmp, serde_json::to_vec(&r)?)?;
+    fs::rename(tmp, output)
+}
+
+/// dir where job inputs and outputs are exchanged with workers, only accessible by
+/// current user. without `state_dir` a new dir is created for every server process
+/// under the shared temp dir, an existing one is never used since others could plant files
+pub fn open_work_dir(state_dir: Option<&str>) -> io::Result<PathBuf> {
+    let mut builder = DirBuilder::new();
+    builder.mode(0o700);
+
+    let dir = match state_dir {
+        Some(state_dir) => {
+            let dir = Path::new(state_dir).join("work");
+            builder.recursive(true).create(&dir)?;
+            fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
+            if let Err(e) = clear_work_dir(&dir) {
+                warn!("clear work dir {:?} failed: {:?}", dir, e);
+            }
+            dir
+        }
+        None => {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            let dir = std::env::temp_dir().join(format!("filecoin-webapi-{}-{:x}", std::process::id(), nanos));
+            builder.create(&dir)?;
+            dir
+        }
+    };
+
+    Ok(dir)
+}
+
+/// remove files left by workers of a previous run, their jobs are interrupted
+fn clear_work_dir(work_dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(work_dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            debug!("remove stale worker file {:?}", path);
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// a job running in a child process
+pub struct JobProcess {
+    child: Child,
+    input: PathBuf,
+    output: PathBuf,
+    stderr: PathBuf,
+    // declared outputs and their length before the job started, `None` if not existed
+    outputs: Vec<(PathBuf, Option<u64>)>,
+}
+
+impl JobProcess {
+    pub fn spawn(kind: &str, token: u64, input: &Value, work_dir: &Path) -> io::Result<Self> {
+        let input_path = work_dir.join(format!("{}.input.json", token));
+        let output_path = work_dir.join(format!("{}.output.json", token));
+        let stderr_path = work_dir.join(format!("{}.stderr.log", token));
+        let outputs = job_outputs(kind, input)
+            .into_iter()
+            .map(|x| {
+                let len = fs::metadata(&x).map(|m| m.len()).ok();
+                (x, len)
+            })
+            .collect();
+        fs::write(&input_path, serde_json::to_vec(input)?)?;
+        let stderr = File::create(&stderr_path)?;
+
+        let mut command = Command::new(std::env::current_exe()?);
+        command
+            .arg(WORKER_COMMAND)
+            .arg("--kind")
+            .arg(kind)
+            .arg("--input")
+            .arg(&input_path)
+            .arg("--output")
+            .arg(&output_path)
+            .stdin(Stdio::null())
+            .stderr(stderr);
+
+        // ctrl-c and service managers signal every process of the server, a worker runs in
+        // its own process group and ignores them, so it only stops when the server kills it.
+        // it's killed as well if the server dies, note the signal is sent once the spawning
+        // thread exits, so workers must be spawned from a thread living as long as the server
+        let server = std::process::id() as libc::pid_t;
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpgid(0, 0) != 0 || libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // server died before the signal was set up
+                if libc::getppid() != server {
+                    return Err(io::Error::new(io::ErrorKind::Other, "server exited"));
+                }
+                libc::signal(libc::SIGINT, libc::SIG_IGN);
+                libc::signal(libc::SIGTERM, libc::SIG_IGN);
+                Ok(())
+            });
+        }
+
+        let child = command.spawn();
+
+        let child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                let _ = fs::remove_file(&input_path);
+                let _ = fs::remove_file(&stderr_path);
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            child,
+            input: input_path,
+            output: output_path,
+            stderr: stderr_path,
+            outputs,
+        })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// return job result if child process exited, abnormal exits are reported as crashed
+    pub fn try_finish(&mut self) -> Option<Result<Value, PollingError>> {
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => status,
+            Ok(None) => return None,
+            Err(e) => {
+                let stderr_tail = format!("wait worker {} failed: {:?}", self.pid(), e);
+                self.cleanup();
+                return Some(Err(PollingError::Crashed {
+                    signal: None,
+                    stderr_tail,
+                }));
+            }
+        };
+
+        let r = match fs::read(&self.output).map(|x| serde_json::from_slice(&x)) {
+            Ok(Ok(r)) if status.success() => Ok(r),
+            _ => {
+                let stderr_tail = self.stderr_tail();
+                warn!("worker {} crashed, {}:\n{}", self.pid(), status, stderr_tail);
+                self.rollback();
+
+                Err(PollingError::Crashed {
+                    signal: status.signal(),
+                    stderr_tail,
+                })
+            }
+        };
+
+        self.cleanup();
+        Some(r)
+    }
+
+    fn stderr_tail(&self) -> String {
+        let mut buf = vec![];
+        let r = File::open(&self.stderr).and_then(|mut f| {
+            let len = f.metadata()?.len();
+            f.seek(SeekFrom::Start(len.saturating_sub(STDERR_TAIL_BYTES)))?;
+            f.read_to_end(&mut buf)
+        });
+
+        if let Err(e) = r {
+            warn!("read {:?} failed: {:?}", self.stderr, e);
+        }
+
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+
+    /// kill child process, the process is always reaped before return
+    /// and partial outputs are rolled back
+    pub fn kill(&mut self) -> io::Result<()> {
+        let r = self.child.kill();
+        self.child.wait()?;
+        self.cleanup();
+        self.rollback();
+
+        r
+    }
+
+    /// remove outputs created by the job, and truncate existing ones to their original length
+    fn rollback(&self) {
+        for (path, len) in &self.outputs {
+            let r = match len {
+                Some(len) => OpenOptions::new().write(true).open(path).and_then(|f| f.set_len(*len)),
+                None => fs::remove_file(path),
+            };
+
+            match r {
+                Ok(()) => debug!("rolled back job output {:?}", path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!("roll back job output {:?} failed: {:?}", path, e),
+            }
+        }
+    }
+
+    fn cleanup(&self) {
+        for path in &[
+            &self.input,
+            &self.output,
+            &self.output.with_extension("json.tmp"),
+            &self.stderr,
+        ] {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("remove {:?} failed: {:?}", path, e);
+                }
+            }
+        }
+    }
+}